@@ -0,0 +1,100 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An IP address range in CIDR notation (i.e. `10.0.0.0/8` or `::1/128`), a bare address matches only itself
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseCidrError;
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+
+        let network: IpAddr = address.parse().map_err(|_| ParseCidrError)?;
+        let max_prefix_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| ParseCidrError)?,
+            None => max_prefix_len,
+        };
+
+        if prefix_len > max_prefix_len {
+            return Err(ParseCidrError);
+        }
+
+        Ok(Cidr {
+            network: network,
+            prefix_len: prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Treat IPv4-mapped IPv6 addresses (i.e. from dual-stack sockets) as IPv4
+        let ip = match ip {
+            IpAddr::V6(ipv6) => match ipv6.to_ipv4_mapped() {
+                Some(ipv4) => IpAddr::V4(ipv4),
+                None => ip,
+            },
+            IpAddr::V4(_) => ip,
+        };
+
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn parse_cidr_list(s: &str) -> Result<Vec<Cidr>, ParseCidrError> {
+    s.split(',')
+        .filter(|cidr| !cidr.trim().is_empty())
+        .map(|cidr| cidr.parse())
+        .collect()
+}
+
+pub fn cidr_list_contains(cidrs: &[Cidr], ip: IpAddr) -> bool {
+    cidrs.iter().any(|cidr| cidr.contains(ip))
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
@@ -1,4 +1,11 @@
-use std::{env, net, path::PathBuf};
+use crate::cidr::{parse_cidr_list, Cidr};
+use crate::protocol::Protocol;
+use std::{
+    collections::HashMap,
+    env, fs, net,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 const DEFAULT_PUBLIC_ROOT_PATH: &str = "public_root";
 const DEFAULT_PARTIALS_PATH: &str = "partials";
@@ -10,90 +17,577 @@ const DEFAULT_TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME: &str = "ca.cert.pem";
 const DEFAULT_TLS_SERVER_CERTIFICATE_PEM_FILENAME: &str = "localhost.cert.pem";
 const DEFAULT_TLS_SERVER_PRIVATE_KEY_PEM_FILENAME: &str = "localhost.pem";
 const DEFAULT_DEFAULT_HOSTNAME: &str = "localhost";
+const DEFAULT_ENABLE_DIRECTORY_LISTING: bool = false;
+const DEFAULT_TEMPLATE_CACHE_ENABLED: bool = true;
+const DEFAULT_HIDDEN_FILES_BLOCKED: bool = true;
+const DEFAULT_ENABLE_RESPONSE_COMPRESSION: bool = false;
+const DEFAULT_MIN_COMPRESSION_BYTES: usize = 1024;
+const DEFAULT_RATE_LIMIT_REQUESTS_PER_SECOND: u32 = 10;
+const DEFAULT_RATE_LIMIT_BURST: u32 = 30;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: u32 = 20;
+const DEFAULT_MAX_TOTAL_CONNECTIONS: u32 = 1000;
+const DEFAULT_HSTS_MAX_AGE_SECONDS: u64 = 31_536_000;
+const DEFAULT_HSTS_INCLUDE_SUBDOMAINS: bool = false;
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'";
+const DEFAULT_X_FRAME_OPTIONS: &str = "SAMEORIGIN";
+const DEFAULT_PERMISSIONS_POLICY: &str = "camera=(), microphone=(), geolocation=()";
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+const REFERRER_POLICIES: &[&str] = &[
+    "no-referrer",
+    "no-referrer-when-downgrade",
+    "origin",
+    "origin-when-cross-origin",
+    "same-origin",
+    "strict-origin",
+    "strict-origin-when-cross-origin",
+    "unsafe-url",
+];
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_REDIRECTS_DATA_FILE: &str = "data/redirects.json";
+const DEFAULT_TRAILING_SLASH_POLICY: &str = "none";
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_MAX_KEEPALIVE_REQUESTS: u32 = 100;
+const DEFAULT_POSTS_SORT_ORDER: &str = "date_desc";
+const DEFAULT_CORS_ALLOW_METHODS: &str = "GET, HEAD, OPTIONS";
+const DEFAULT_CORS_ALLOW_CREDENTIALS: bool = false;
+const DEFAULT_MAX_RESPONSE_BODY_BYTES: u64 = 50 * 1024 * 1024;
+const DEFAULT_BLOCKED_FILE_EXTENSIONS: &str = "pem,key,env,git,htpasswd";
+const DEFAULT_STREAMING_THRESHOLD_BYTES: u64 = 1024 * 1024;
+const DEFAULT_MAX_FS_CACHE_ENTRIES: usize = 512;
+const DEFAULT_FS_CACHE_LONG_TTL_MS: u64 = 14_400_000;
+const DEFAULT_FS_CACHE_SHORT_TTL_MS: u64 = 10_000;
+const DEFAULT_FS_CACHE_SHORT_TTL_EXTENSIONS: &str = "hbs,html,gmi,md,json";
+const DEFAULT_MAX_DATA_CACHE_ENTRIES: usize = 512;
+
+// Keys whose lists aren't comma-separated, for joining TOML arrays in the config file
+const LIST_SEPARATORS: &[(&str, &str)] = &[
+    ("PARTIALS_PATH", ":"),
+    ("VIRTUAL_HOSTS", ";"),
+    ("TLS_SNI_CERTS", ";"),
+    ("EXTRA_RESPONSE_HEADERS", ";;"),
+];
 
 #[derive(Clone, Debug)]
 pub struct Config {
     public_root_path: String,
-    partials_path: String,
+    partials_paths: Vec<String>,
     data_path: String,
     errdocs_path: String,
+    gemini_public_root_path: Option<String>,
+    https_public_root_path: Option<String>,
+    gemini_errdocs_path: Option<String>,
+    https_errdocs_path: Option<String>,
     max_request_header_size: usize,
-    tls_listen_bind: net::SocketAddrV4,
-    tls_client_ca_certificate_pem_filename: String,
+    tls_listen_binds: Vec<net::SocketAddr>,
+    plain_redirect_bind: Option<net::SocketAddr>,
+    tls_client_ca_certificate_pem_filenames: Vec<String>,
     tls_server_certificate_pem_filename: String,
     tls_server_private_key_pem_filename: String,
     default_hostname: String,
+    enable_directory_listing: bool,
+    template_cache_enabled: bool,
+    hidden_files_blocked: bool,
+    enable_response_compression: bool,
+    min_compression_bytes: usize,
+    virtual_hosts: HashMap<String, String>,
+    custom_mime_types: HashMap<String, String>,
+    blocked_file_extensions: Vec<String>,
+    rate_limit_requests_per_second: u32,
+    rate_limit_burst: u32,
+    max_connections_per_ip: u32,
+    max_total_connections: u32,
+    hsts_max_age_seconds: u64,
+    hsts_include_subdomains: bool,
+    content_security_policy: String,
+    x_frame_options: String,
+    permissions_policy: String,
+    referrer_policy: String,
+    extra_response_headers: Vec<(String, String)>,
+    well_known_path: Option<String>,
+    metrics_path: String,
+    robots_txt: String,
+    shutdown_timeout_seconds: u64,
+    tls_sni_certs: HashMap<String, (String, String)>,
+    redirects_data_file: String,
+    trailing_slash_policy: TrailingSlashPolicy,
+    trusted_proxy_cidrs: Vec<Cidr>,
+    ip_allowlist: Vec<Cidr>,
+    ip_denylist: Vec<Cidr>,
+    request_timeout_ms: u64,
+    tls_handshake_timeout_ms: u64,
+    max_keepalive_requests: u32,
+    posts_sort_order: PostsSortOrder,
+    cors_allow_origins: Vec<String>,
+    cors_allow_methods: String,
+    cors_allow_credentials: bool,
+    max_response_body_bytes: u64,
+    streaming_threshold_bytes: u64,
+    max_fs_cache_entries: usize,
+    fs_cache_long_ttl_ms: u64,
+    fs_cache_short_ttl_ms: u64,
+    fs_cache_short_ttl_extensions: Vec<String>,
+    max_data_cache_entries: usize,
 }
 
 impl Config {
     pub fn new_from_env() -> Config {
+        Config::new_from_lookup(|key| env::var(key).ok())
+    }
+
+    // Keys in the file are the same as the environment variables, but in snake_case (i.e. public_root_path)
+    pub fn new_from_file(path: &Path) -> Config {
+        let config_str = fs::read_to_string(path).expect("Could not read config file");
+        let config_table: toml::value::Table =
+            toml::from_str(&config_str).expect("Invalid config file");
+
+        Config::new_from_lookup(|key| {
+            config_table
+                .get(&key.to_lowercase())
+                .map(|value| toml_value_to_config_str(key, value))
+        })
+    }
+
+    fn new_from_lookup<F>(lookup: F) -> Config
+    where
+        F: Fn(&str) -> Option<String>,
+    {
         let public_root_path = check_directory_path(
-            &env::var("PUBLIC_ROOT_PATH").unwrap_or(DEFAULT_PUBLIC_ROOT_PATH.into()),
+            &lookup("PUBLIC_ROOT_PATH").unwrap_or(DEFAULT_PUBLIC_ROOT_PATH.into()),
         )
         .expect("Invalid PUBLIC_ROOT_PATH")
         .to_string();
 
-        let partials_path = check_directory_path(
-            &env::var("PARTIALS_PATH").unwrap_or(DEFAULT_PARTIALS_PATH.into()),
-        )
-        .expect("Invalid PARTIALS_PATH")
-        .to_string();
+        // Colon-separated, with partials in later folders overriding ones with the same name
+        let partials_paths: Vec<String> = lookup("PARTIALS_PATH")
+            .unwrap_or(DEFAULT_PARTIALS_PATH.into())
+            .split(':')
+            .filter(|partials_path| !partials_path.trim().is_empty())
+            .map(|partials_path| {
+                check_directory_path(partials_path.trim()).expect("Invalid PARTIALS_PATH")
+            })
+            .collect();
 
         let data_path =
-            check_directory_path(&env::var("DATA_PATH").unwrap_or(DEFAULT_DATA_PATH.into()))
+            check_directory_path(&lookup("DATA_PATH").unwrap_or(DEFAULT_DATA_PATH.into()))
                 .expect("Invalid DATA_PATH")
                 .to_string();
 
         let errdocs_path =
-            check_directory_path(&env::var("ERRDOCS_PATH").unwrap_or(DEFAULT_ERRDOCS_PATH.into()))
+            check_directory_path(&lookup("ERRDOCS_PATH").unwrap_or(DEFAULT_ERRDOCS_PATH.into()))
                 .expect("Invalid ERRDOCS_PATH")
                 .to_string();
 
-        let max_request_header_size: usize = env::var("MAX_REQUEST_HEADER_SIZE")
+        // Protocol-specific paths are optional and fall back to the generic ones
+        let optional_directory_path = |key: &str| -> Option<String> {
+            lookup(key).filter(|path| !path.is_empty()).map(|path| {
+                check_directory_path(&path).unwrap_or_else(|_| panic!("Invalid {}", key))
+            })
+        };
+        let gemini_public_root_path = optional_directory_path("GEMINI_PUBLIC_ROOT_PATH");
+        let https_public_root_path = optional_directory_path("HTTPS_PUBLIC_ROOT_PATH");
+        let gemini_errdocs_path = optional_directory_path("GEMINI_ERRDOCS_PATH");
+        let https_errdocs_path = optional_directory_path("HTTPS_ERRDOCS_PATH");
+
+        let max_request_header_size: usize = lookup("MAX_REQUEST_HEADER_SIZE")
             .unwrap_or(format!("{}", DEFAULT_MAX_REQUEST_HEADER_SIZE))
             .parse()
             .expect("Invalid MAX_REQUEST_HEADER_SIZE");
 
-        let tls_listen_bind: net::SocketAddrV4 = env::var("TLS_LISTEN_BIND")
+        let tls_listen_binds: Vec<net::SocketAddr> = lookup("TLS_LISTEN_BIND")
             .unwrap_or(DEFAULT_TLS_LISTEN_BIND.to_string())
-            .parse()
-            .expect("Invalid TLS_LISTEN_BIND");
+            .split(',')
+            .map(|bind| bind.trim())
+            .filter(|bind| !bind.is_empty())
+            .map(|bind| bind.parse().expect("Invalid TLS_LISTEN_BIND"))
+            .collect();
 
-        let tls_client_ca_certificate_pem_filename = check_file_path(
-            &env::var("TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME")
-                .unwrap_or(DEFAULT_TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME.into()),
-        )
-        .expect("Invalid TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME")
-        .to_string();
+        if tls_listen_binds.is_empty() {
+            panic!("Invalid TLS_LISTEN_BIND: at least one address is required");
+        }
+
+        let plain_redirect_bind: Option<net::SocketAddr> = match lookup("PLAIN_REDIRECT_BIND") {
+            Some(bind) if !bind.trim().is_empty() => {
+                Some(bind.trim().parse().expect("Invalid PLAIN_REDIRECT_BIND"))
+            }
+            _ => None,
+        };
+
+        // Multiple CA files take precedence over the single file
+        let tls_client_ca_certificate_pem_filenames: Vec<String> =
+            match lookup("TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAMES") {
+                Some(filenames) if !filenames.trim().is_empty() => filenames
+                    .split(',')
+                    .map(|filename| filename.trim())
+                    .filter(|filename| !filename.is_empty())
+                    .map(|filename| {
+                        check_file_path(filename)
+                            .expect("Invalid TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAMES")
+                    })
+                    .collect(),
+                _ => vec![check_file_path(
+                    &lookup("TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME")
+                        .unwrap_or(DEFAULT_TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME.into()),
+                )
+                .expect("Invalid TLS_CLIENT_CA_CERTIFICATE_PEM_FILENAME")],
+            };
 
         let tls_server_certificate_pem_filename = check_file_path(
-            &env::var("TLS_SERVER_CERTIFICATE_PEM_FILENAME")
+            &lookup("TLS_SERVER_CERTIFICATE_PEM_FILENAME")
                 .unwrap_or(DEFAULT_TLS_SERVER_CERTIFICATE_PEM_FILENAME.into()),
         )
         .expect("Invalid TLS_SERVER_CERTIFICATE_PEM_FILENAME")
         .to_string();
 
         let tls_server_private_key_pem_filename = check_file_path(
-            &env::var("TLS_SERVER_PRIVATE_KEY_PEM_FILENAME")
+            &lookup("TLS_SERVER_PRIVATE_KEY_PEM_FILENAME")
                 .unwrap_or(DEFAULT_TLS_SERVER_PRIVATE_KEY_PEM_FILENAME.into()),
         )
         .expect("Invalid TLS_SERVER_PRIVATE_KEY_PEM_FILENAME")
         .to_string();
 
         let default_hostname =
-            env::var("DEFAULT_HOSTNAME").unwrap_or(DEFAULT_DEFAULT_HOSTNAME.into());
+            lookup("DEFAULT_HOSTNAME").unwrap_or(DEFAULT_DEFAULT_HOSTNAME.into());
+
+        let enable_directory_listing: bool = lookup("ENABLE_DIRECTORY_LISTING")
+            .unwrap_or(format!("{}", DEFAULT_ENABLE_DIRECTORY_LISTING))
+            .parse()
+            .expect("Invalid ENABLE_DIRECTORY_LISTING");
+
+        let template_cache_enabled: bool = lookup("TEMPLATE_CACHE_ENABLED")
+            .unwrap_or(format!("{}", DEFAULT_TEMPLATE_CACHE_ENABLED))
+            .parse()
+            .expect("Invalid TEMPLATE_CACHE_ENABLED");
+
+        let hidden_files_blocked: bool = lookup("HIDDEN_FILES_BLOCKED")
+            .unwrap_or(format!("{}", DEFAULT_HIDDEN_FILES_BLOCKED))
+            .parse()
+            .expect("Invalid HIDDEN_FILES_BLOCKED");
+
+        let enable_response_compression: bool = lookup("ENABLE_RESPONSE_COMPRESSION")
+            .unwrap_or(format!("{}", DEFAULT_ENABLE_RESPONSE_COMPRESSION))
+            .parse()
+            .expect("Invalid ENABLE_RESPONSE_COMPRESSION");
+
+        let min_compression_bytes: usize = lookup("MIN_COMPRESSION_BYTES")
+            .unwrap_or(format!("{}", DEFAULT_MIN_COMPRESSION_BYTES))
+            .parse()
+            .expect("Invalid MIN_COMPRESSION_BYTES");
+
+        let virtual_hosts: HashMap<String, String> = lookup("VIRTUAL_HOSTS")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|virtual_host| !virtual_host.trim().is_empty())
+            .map(|virtual_host| {
+                let (hostname, path) = virtual_host
+                    .split_once(':')
+                    .expect("Invalid VIRTUAL_HOSTS (expected hostname:path)");
+                (
+                    hostname.trim().to_ascii_lowercase(),
+                    check_directory_path(path.trim()).expect("Invalid VIRTUAL_HOSTS path"),
+                )
+            })
+            .collect();
+
+        // Extensions are matched case-insensitively, with or without the leading dot
+        let custom_mime_types: HashMap<String, String> = lookup("CUSTOM_MIME_TYPES")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|custom_mime_type| !custom_mime_type.trim().is_empty())
+            .map(|custom_mime_type| {
+                let (extension, mime_type) = custom_mime_type
+                    .split_once(':')
+                    .expect("Invalid CUSTOM_MIME_TYPES (expected ext:mime_type)");
+                (
+                    extension
+                        .trim()
+                        .trim_start_matches('.')
+                        .to_ascii_lowercase(),
+                    mime_type.trim().to_string(),
+                )
+            })
+            .collect();
+
+        // Empty blocks nothing
+        let blocked_file_extensions: Vec<String> = lookup("BLOCKED_FILE_EXTENSIONS")
+            .unwrap_or(DEFAULT_BLOCKED_FILE_EXTENSIONS.into())
+            .split(',')
+            .map(|extension| {
+                extension
+                    .trim()
+                    .trim_start_matches('.')
+                    .to_ascii_lowercase()
+            })
+            .filter(|extension| !extension.is_empty())
+            .collect();
+
+        let rate_limit_requests_per_second: u32 = lookup("RATE_LIMIT_REQUESTS_PER_SECOND")
+            .unwrap_or(format!("{}", DEFAULT_RATE_LIMIT_REQUESTS_PER_SECOND))
+            .parse()
+            .expect("Invalid RATE_LIMIT_REQUESTS_PER_SECOND");
+
+        let rate_limit_burst: u32 = lookup("RATE_LIMIT_BURST")
+            .unwrap_or(format!("{}", DEFAULT_RATE_LIMIT_BURST))
+            .parse()
+            .expect("Invalid RATE_LIMIT_BURST");
+
+        let max_connections_per_ip: u32 = lookup("MAX_CONNECTIONS_PER_IP")
+            .unwrap_or(format!("{}", DEFAULT_MAX_CONNECTIONS_PER_IP))
+            .parse()
+            .expect("Invalid MAX_CONNECTIONS_PER_IP");
+
+        let max_total_connections: u32 = lookup("MAX_TOTAL_CONNECTIONS")
+            .unwrap_or(format!("{}", DEFAULT_MAX_TOTAL_CONNECTIONS))
+            .parse()
+            .expect("Invalid MAX_TOTAL_CONNECTIONS");
+
+        let hsts_max_age_seconds: u64 = lookup("HSTS_MAX_AGE_SECONDS")
+            .unwrap_or(format!("{}", DEFAULT_HSTS_MAX_AGE_SECONDS))
+            .parse()
+            .expect("Invalid HSTS_MAX_AGE_SECONDS");
+
+        let hsts_include_subdomains: bool = lookup("HSTS_INCLUDE_SUBDOMAINS")
+            .unwrap_or(format!("{}", DEFAULT_HSTS_INCLUDE_SUBDOMAINS))
+            .parse()
+            .expect("Invalid HSTS_INCLUDE_SUBDOMAINS");
+
+        let content_security_policy =
+            lookup("CONTENT_SECURITY_POLICY").unwrap_or(DEFAULT_CONTENT_SECURITY_POLICY.into());
+
+        // Empty sends no X-Frame-Options header (i.e. for sites embedded in iframes elsewhere)
+        let x_frame_options = lookup("X_FRAME_OPTIONS").unwrap_or(DEFAULT_X_FRAME_OPTIONS.into());
+
+        // Passed through as is since the format is complex, empty sends no Permissions-Policy header
+        let permissions_policy =
+            lookup("PERMISSIONS_POLICY").unwrap_or(DEFAULT_PERMISSIONS_POLICY.into());
+        if permissions_policy.contains(|c| c == '\r' || c == '\n') {
+            panic!("Invalid PERMISSIONS_POLICY (must not contain newlines)");
+        }
+
+        // Empty sends no Referrer-Policy header, leaving it up to the browser
+        let referrer_policy = lookup("REFERRER_POLICY").unwrap_or(DEFAULT_REFERRER_POLICY.into());
+        if !referrer_policy.is_empty() && !REFERRER_POLICIES.contains(&referrer_policy.as_str()) {
+            panic!(
+                "Invalid REFERRER_POLICY {:?} (expected one of {})",
+                referrer_policy,
+                REFERRER_POLICIES.join(", ")
+            );
+        }
+
+        // Separated by ;; as header values can contain commas and semicolons
+        let extra_response_headers: Vec<(String, String)> = lookup("EXTRA_RESPONSE_HEADERS")
+            .unwrap_or_default()
+            .split(";;")
+            .filter(|header| !header.trim().is_empty())
+            .map(|header| {
+                let (name, value) = header
+                    .split_once(':')
+                    .expect("Invalid EXTRA_RESPONSE_HEADERS (expected Header-Name: Value)");
+                let (name, value) = (name.trim(), value.trim());
+
+                if !is_valid_header_name(name) {
+                    panic!("Invalid EXTRA_RESPONSE_HEADERS header name: {:?}", name);
+                }
+                if value.contains(|c: char| c.is_control() && c != '\t') {
+                    panic!(
+                        "Invalid EXTRA_RESPONSE_HEADERS value for {}: {:?}",
+                        name, value
+                    );
+                }
+
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+
+        let well_known_path = optional_directory_path("WELL_KNOWN_PATH");
+
+        // Empty (the default) disables the metrics endpoint
+        let metrics_path = lookup("METRICS_PATH").unwrap_or_default();
+
+        // Empty (the default) serves robots.txt from PUBLIC_ROOT_PATH like any other file
+        let robots_txt = lookup("ROBOTS_TXT")
+            .unwrap_or_default()
+            .replace("\\n", "\n");
+
+        let shutdown_timeout_seconds: u64 = lookup("SHUTDOWN_TIMEOUT_SECONDS")
+            .unwrap_or(format!("{}", DEFAULT_SHUTDOWN_TIMEOUT_SECONDS))
+            .parse()
+            .expect("Invalid SHUTDOWN_TIMEOUT_SECONDS");
+
+        let tls_sni_certs: HashMap<String, (String, String)> = lookup("TLS_SNI_CERTS")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|sni_cert| !sni_cert.trim().is_empty())
+            .map(|sni_cert| {
+                let mut parts = sni_cert.splitn(3, ':').map(|part| part.trim());
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(hostname), Some(cert_path), Some(key_path)) => (
+                        hostname.to_ascii_lowercase(),
+                        (
+                            check_file_path(cert_path)
+                                .expect("Invalid TLS_SNI_CERTS certificate path"),
+                            check_file_path(key_path)
+                                .expect("Invalid TLS_SNI_CERTS private key path"),
+                        ),
+                    ),
+                    _ => panic!("Invalid TLS_SNI_CERTS (expected hostname:cert_pem:key_pem)"),
+                }
+            })
+            .collect();
+
+        // Not checked here as the file is optional
+        let redirects_data_file =
+            lookup("REDIRECTS_DATA_FILE").unwrap_or(DEFAULT_REDIRECTS_DATA_FILE.into());
+
+        let trailing_slash_policy: TrailingSlashPolicy = lookup("TRAILING_SLASH_POLICY")
+            .unwrap_or(DEFAULT_TRAILING_SLASH_POLICY.into())
+            .parse()
+            .expect("Invalid TRAILING_SLASH_POLICY (expected add, remove or none)");
+
+        let trusted_proxy_cidrs =
+            parse_cidr_list(&lookup("TRUSTED_PROXY_CIDRS").unwrap_or_default())
+                .expect("Invalid TRUSTED_PROXY_CIDRS (expected comma-separated CIDR ranges)");
+
+        let ip_allowlist = parse_cidr_list(&lookup("IP_ALLOWLIST").unwrap_or_default())
+            .expect("Invalid IP_ALLOWLIST (expected comma-separated CIDR ranges)");
+
+        let ip_denylist = parse_cidr_list(&lookup("IP_DENYLIST").unwrap_or_default())
+            .expect("Invalid IP_DENYLIST (expected comma-separated CIDR ranges)");
+
+        let request_timeout_ms: u64 = lookup("REQUEST_TIMEOUT_MS")
+            .unwrap_or(format!("{}", DEFAULT_REQUEST_TIMEOUT_MS))
+            .parse()
+            .expect("Invalid REQUEST_TIMEOUT_MS");
+
+        let tls_handshake_timeout_ms: u64 = lookup("TLS_HANDSHAKE_TIMEOUT_MS")
+            .unwrap_or(format!("{}", DEFAULT_TLS_HANDSHAKE_TIMEOUT_MS))
+            .parse()
+            .expect("Invalid TLS_HANDSHAKE_TIMEOUT_MS");
+
+        let max_keepalive_requests: u32 = lookup("MAX_KEEPALIVE_REQUESTS")
+            .unwrap_or(format!("{}", DEFAULT_MAX_KEEPALIVE_REQUESTS))
+            .parse()
+            .expect("Invalid MAX_KEEPALIVE_REQUESTS");
+
+        let posts_sort_order: PostsSortOrder = lookup("POSTS_SORT_ORDER")
+            .unwrap_or(DEFAULT_POSTS_SORT_ORDER.into())
+            .parse()
+            .expect(
+                "Invalid POSTS_SORT_ORDER (expected date_desc, date_asc, title_asc or title_desc)",
+            );
+
+        // Empty (the default) sends no CORS headers
+        let cors_allow_origins: Vec<String> = lookup("CORS_ALLOW_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        let cors_allow_methods =
+            lookup("CORS_ALLOW_METHODS").unwrap_or(DEFAULT_CORS_ALLOW_METHODS.into());
+
+        let cors_allow_credentials: bool = lookup("CORS_ALLOW_CREDENTIALS")
+            .unwrap_or(format!("{}", DEFAULT_CORS_ALLOW_CREDENTIALS))
+            .parse()
+            .expect("Invalid CORS_ALLOW_CREDENTIALS");
+
+        let max_response_body_bytes: u64 = lookup("MAX_RESPONSE_BODY_BYTES")
+            .unwrap_or(format!("{}", DEFAULT_MAX_RESPONSE_BODY_BYTES))
+            .parse()
+            .expect("Invalid MAX_RESPONSE_BODY_BYTES");
+
+        let streaming_threshold_bytes: u64 = lookup("STREAMING_THRESHOLD_BYTES")
+            .unwrap_or(format!("{}", DEFAULT_STREAMING_THRESHOLD_BYTES))
+            .parse()
+            .expect("Invalid STREAMING_THRESHOLD_BYTES");
+
+        let max_fs_cache_entries: usize = lookup("MAX_FS_CACHE_ENTRIES")
+            .unwrap_or(format!("{}", DEFAULT_MAX_FS_CACHE_ENTRIES))
+            .parse()
+            .expect("Invalid MAX_FS_CACHE_ENTRIES");
+
+        let fs_cache_long_ttl_ms: u64 = lookup("FS_CACHE_LONG_TTL_MS")
+            .unwrap_or(format!("{}", DEFAULT_FS_CACHE_LONG_TTL_MS))
+            .parse()
+            .expect("Invalid FS_CACHE_LONG_TTL_MS");
+
+        let fs_cache_short_ttl_ms: u64 = lookup("FS_CACHE_SHORT_TTL_MS")
+            .unwrap_or(format!("{}", DEFAULT_FS_CACHE_SHORT_TTL_MS))
+            .parse()
+            .expect("Invalid FS_CACHE_SHORT_TTL_MS");
+
+        let fs_cache_short_ttl_extensions: Vec<String> = lookup("FS_CACHE_SHORT_TTL_EXTENSIONS")
+            .unwrap_or(DEFAULT_FS_CACHE_SHORT_TTL_EXTENSIONS.into())
+            .split(',')
+            .map(|extension| extension.trim().trim_start_matches('.').to_string())
+            .filter(|extension| !extension.is_empty())
+            .collect();
+
+        let max_data_cache_entries: usize = lookup("MAX_DATA_CACHE_ENTRIES")
+            .unwrap_or(format!("{}", DEFAULT_MAX_DATA_CACHE_ENTRIES))
+            .parse()
+            .expect("Invalid MAX_DATA_CACHE_ENTRIES");
 
         Config {
             public_root_path: public_root_path.into(),
-            partials_path: partials_path.into(),
+            partials_paths: partials_paths,
             data_path: data_path.into(),
             errdocs_path: errdocs_path.into(),
+            gemini_public_root_path: gemini_public_root_path,
+            https_public_root_path: https_public_root_path,
+            gemini_errdocs_path: gemini_errdocs_path,
+            https_errdocs_path: https_errdocs_path,
             max_request_header_size: max_request_header_size,
-            tls_listen_bind: tls_listen_bind,
-            tls_client_ca_certificate_pem_filename: tls_client_ca_certificate_pem_filename.into(),
+            tls_listen_binds: tls_listen_binds,
+            plain_redirect_bind: plain_redirect_bind,
+            tls_client_ca_certificate_pem_filenames: tls_client_ca_certificate_pem_filenames,
             tls_server_certificate_pem_filename: tls_server_certificate_pem_filename.into(),
             tls_server_private_key_pem_filename: tls_server_private_key_pem_filename.into(),
             default_hostname: default_hostname,
+            enable_directory_listing: enable_directory_listing,
+            template_cache_enabled: template_cache_enabled,
+            hidden_files_blocked: hidden_files_blocked,
+            enable_response_compression: enable_response_compression,
+            min_compression_bytes: min_compression_bytes,
+            virtual_hosts: virtual_hosts,
+            custom_mime_types: custom_mime_types,
+            blocked_file_extensions: blocked_file_extensions,
+            rate_limit_requests_per_second: rate_limit_requests_per_second,
+            rate_limit_burst: rate_limit_burst,
+            max_connections_per_ip: max_connections_per_ip,
+            max_total_connections: max_total_connections,
+            hsts_max_age_seconds: hsts_max_age_seconds,
+            hsts_include_subdomains: hsts_include_subdomains,
+            content_security_policy: content_security_policy,
+            x_frame_options: x_frame_options,
+            permissions_policy: permissions_policy,
+            referrer_policy: referrer_policy,
+            extra_response_headers: extra_response_headers,
+            well_known_path: well_known_path,
+            metrics_path: metrics_path,
+            robots_txt: robots_txt,
+            shutdown_timeout_seconds: shutdown_timeout_seconds,
+            tls_sni_certs: tls_sni_certs,
+            redirects_data_file: redirects_data_file,
+            trailing_slash_policy: trailing_slash_policy,
+            trusted_proxy_cidrs: trusted_proxy_cidrs,
+            ip_allowlist: ip_allowlist,
+            ip_denylist: ip_denylist,
+            request_timeout_ms: request_timeout_ms,
+            tls_handshake_timeout_ms: tls_handshake_timeout_ms,
+            max_keepalive_requests: max_keepalive_requests,
+            posts_sort_order: posts_sort_order,
+            cors_allow_origins: cors_allow_origins,
+            cors_allow_methods: cors_allow_methods,
+            cors_allow_credentials: cors_allow_credentials,
+            max_response_body_bytes: max_response_body_bytes,
+            streaming_threshold_bytes: streaming_threshold_bytes,
+            max_fs_cache_entries: max_fs_cache_entries,
+            fs_cache_long_ttl_ms: fs_cache_long_ttl_ms,
+            fs_cache_short_ttl_ms: fs_cache_short_ttl_ms,
+            fs_cache_short_ttl_extensions: fs_cache_short_ttl_extensions,
+            max_data_cache_entries: max_data_cache_entries,
         }
     }
 
@@ -101,8 +595,8 @@ impl Config {
         &self.public_root_path
     }
 
-    pub fn partials_path(&self) -> &str {
-        &self.partials_path
+    pub fn partials_paths(&self) -> &[String] {
+        &self.partials_paths
     }
 
     pub fn data_path(&self) -> &str {
@@ -113,16 +607,56 @@ impl Config {
         &self.errdocs_path
     }
 
+    pub fn gemini_public_root_path(&self) -> Option<&str> {
+        self.gemini_public_root_path.as_deref()
+    }
+
+    pub fn https_public_root_path(&self) -> Option<&str> {
+        self.https_public_root_path.as_deref()
+    }
+
+    pub fn gemini_errdocs_path(&self) -> Option<&str> {
+        self.gemini_errdocs_path.as_deref()
+    }
+
+    pub fn https_errdocs_path(&self) -> Option<&str> {
+        self.https_errdocs_path.as_deref()
+    }
+
+    // Falls back to PUBLIC_ROOT_PATH if there isn't one for the protocol
+    pub fn public_root_path_for_protocol(&self, protocol: Protocol) -> &str {
+        let protocol_public_root_path = match protocol {
+            Protocol::Gemini => self.gemini_public_root_path(),
+            Protocol::Https => self.https_public_root_path(),
+        };
+
+        protocol_public_root_path.unwrap_or(&self.public_root_path)
+    }
+
+    // Falls back to ERRDOCS_PATH if there isn't one for the protocol
+    pub fn errdocs_path_for_protocol(&self, protocol: Protocol) -> &str {
+        let protocol_errdocs_path = match protocol {
+            Protocol::Gemini => self.gemini_errdocs_path(),
+            Protocol::Https => self.https_errdocs_path(),
+        };
+
+        protocol_errdocs_path.unwrap_or(&self.errdocs_path)
+    }
+
     pub fn max_request_header_size(&self) -> usize {
         self.max_request_header_size
     }
 
-    pub fn tls_listen_bind(&self) -> &net::SocketAddrV4 {
-        &self.tls_listen_bind
+    pub fn tls_listen_binds(&self) -> &[net::SocketAddr] {
+        &self.tls_listen_binds
     }
 
-    pub fn tls_client_ca_certificate_pem_filename(&self) -> &str {
-        &self.tls_client_ca_certificate_pem_filename
+    pub fn plain_redirect_bind(&self) -> Option<&net::SocketAddr> {
+        self.plain_redirect_bind.as_ref()
+    }
+
+    pub fn tls_client_ca_certificate_pem_filenames(&self) -> &[String] {
+        &self.tls_client_ca_certificate_pem_filenames
     }
 
     pub fn tls_server_certificate_pem_filename(&self) -> &str {
@@ -136,11 +670,287 @@ impl Config {
     pub fn default_hostname(&self) -> &str {
         &self.default_hostname
     }
+
+    pub fn enable_directory_listing(&self) -> bool {
+        self.enable_directory_listing
+    }
+
+    pub fn template_cache_enabled(&self) -> bool {
+        self.template_cache_enabled
+    }
+
+    pub fn hidden_files_blocked(&self) -> bool {
+        self.hidden_files_blocked
+    }
+
+    pub fn enable_response_compression(&self) -> bool {
+        self.enable_response_compression
+    }
+
+    pub fn min_compression_bytes(&self) -> usize {
+        self.min_compression_bytes
+    }
+
+    pub fn virtual_hosts(&self) -> &HashMap<String, String> {
+        &self.virtual_hosts
+    }
+
+    pub fn custom_mime_types(&self) -> &HashMap<String, String> {
+        &self.custom_mime_types
+    }
+
+    pub fn blocked_file_extensions(&self) -> &[String] {
+        &self.blocked_file_extensions
+    }
+
+    // Virtual hosts take precedence over the public root for the protocol
+    pub fn public_root_path_for(&self, protocol: Protocol, hostname: &str) -> &str {
+        match self.virtual_hosts.get(&hostname.to_ascii_lowercase()) {
+            Some(path) => path,
+            None => self.public_root_path_for_protocol(protocol),
+        }
+    }
+
+    pub fn rate_limit_requests_per_second(&self) -> u32 {
+        self.rate_limit_requests_per_second
+    }
+
+    pub fn rate_limit_burst(&self) -> u32 {
+        self.rate_limit_burst
+    }
+
+    pub fn max_connections_per_ip(&self) -> u32 {
+        self.max_connections_per_ip
+    }
+
+    pub fn max_total_connections(&self) -> u32 {
+        self.max_total_connections
+    }
+
+    pub fn hsts_max_age_seconds(&self) -> u64 {
+        self.hsts_max_age_seconds
+    }
+
+    pub fn hsts_include_subdomains(&self) -> bool {
+        self.hsts_include_subdomains
+    }
+
+    pub fn content_security_policy(&self) -> &str {
+        &self.content_security_policy
+    }
+
+    pub fn x_frame_options(&self) -> &str {
+        &self.x_frame_options
+    }
+
+    pub fn permissions_policy(&self) -> &str {
+        &self.permissions_policy
+    }
+
+    pub fn referrer_policy(&self) -> &str {
+        &self.referrer_policy
+    }
+
+    pub fn extra_response_headers(&self) -> &[(String, String)] {
+        &self.extra_response_headers
+    }
+
+    pub fn well_known_path(&self) -> Option<&str> {
+        self.well_known_path.as_deref()
+    }
+
+    pub fn metrics_path(&self) -> Option<&str> {
+        match self.metrics_path.is_empty() {
+            true => None,
+            false => Some(&self.metrics_path),
+        }
+    }
+
+    pub fn robots_txt(&self) -> Option<&str> {
+        match self.robots_txt.is_empty() {
+            true => None,
+            false => Some(&self.robots_txt),
+        }
+    }
+
+    pub fn shutdown_timeout_seconds(&self) -> u64 {
+        self.shutdown_timeout_seconds
+    }
+
+    // Hostname to (certificate PEM filename, private key PEM filename)
+    pub fn tls_sni_certs(&self) -> &HashMap<String, (String, String)> {
+        &self.tls_sni_certs
+    }
+
+    pub fn redirects_data_file(&self) -> &str {
+        &self.redirects_data_file
+    }
+
+    pub fn trailing_slash_policy(&self) -> TrailingSlashPolicy {
+        self.trailing_slash_policy
+    }
+
+    pub fn trusted_proxy_cidrs(&self) -> &[Cidr] {
+        &self.trusted_proxy_cidrs
+    }
+
+    pub fn ip_allowlist(&self) -> &[Cidr] {
+        &self.ip_allowlist
+    }
+
+    pub fn ip_denylist(&self) -> &[Cidr] {
+        &self.ip_denylist
+    }
+
+    pub fn request_timeout_ms(&self) -> u64 {
+        self.request_timeout_ms
+    }
+
+    pub fn tls_handshake_timeout_ms(&self) -> u64 {
+        self.tls_handshake_timeout_ms
+    }
+
+    pub fn max_keepalive_requests(&self) -> u32 {
+        self.max_keepalive_requests
+    }
+
+    pub fn posts_sort_order(&self) -> PostsSortOrder {
+        self.posts_sort_order
+    }
+
+    pub fn cors_allow_origins(&self) -> &[String] {
+        &self.cors_allow_origins
+    }
+
+    pub fn cors_allow_methods(&self) -> &str {
+        &self.cors_allow_methods
+    }
+
+    pub fn cors_allow_credentials(&self) -> bool {
+        self.cors_allow_credentials
+    }
+
+    pub fn max_response_body_bytes(&self) -> u64 {
+        self.max_response_body_bytes
+    }
+
+    pub fn streaming_threshold_bytes(&self) -> u64 {
+        self.streaming_threshold_bytes
+    }
+
+    pub fn max_fs_cache_entries(&self) -> usize {
+        self.max_fs_cache_entries
+    }
+
+    pub fn fs_cache_long_ttl_ms(&self) -> u64 {
+        self.fs_cache_long_ttl_ms
+    }
+
+    pub fn fs_cache_short_ttl_ms(&self) -> u64 {
+        self.fs_cache_short_ttl_ms
+    }
+
+    pub fn fs_cache_short_ttl_extensions(&self) -> &[String] {
+        &self.fs_cache_short_ttl_extensions
+    }
+
+    pub fn max_data_cache_entries(&self) -> usize {
+        self.max_data_cache_entries
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct PathError;
 
+// Whether requests for directories should be redirected to have (or not have) a trailing slash
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrailingSlashPolicy {
+    Add,
+    Remove,
+    None,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseTrailingSlashPolicyError;
+
+impl FromStr for TrailingSlashPolicy {
+    type Err = ParseTrailingSlashPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "add" => Ok(TrailingSlashPolicy::Add),
+            "remove" => Ok(TrailingSlashPolicy::Remove),
+            "none" => Ok(TrailingSlashPolicy::None),
+            _ => Err(ParseTrailingSlashPolicyError),
+        }
+    }
+}
+
+// The order of `posts` in templates (feeds are always newest first)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PostsSortOrder {
+    DateDesc,
+    DateAsc,
+    TitleAsc,
+    TitleDesc,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsePostsSortOrderError;
+
+impl FromStr for PostsSortOrder {
+    type Err = ParsePostsSortOrderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "date_desc" => Ok(PostsSortOrder::DateDesc),
+            "date_asc" => Ok(PostsSortOrder::DateAsc),
+            "title_asc" => Ok(PostsSortOrder::TitleAsc),
+            "title_desc" => Ok(PostsSortOrder::TitleDesc),
+            _ => Err(ParsePostsSortOrderError),
+        }
+    }
+}
+
+// Arrays are joined into the same list string the environment variable would have
+fn toml_value_to_config_str(key: &str, value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(value) => value.to_string(),
+        toml::Value::Array(values) => {
+            let separator = LIST_SEPARATORS
+                .iter()
+                .find(|(list_key, _)| *list_key == key)
+                .map(|(_, separator)| *separator)
+                .unwrap_or(",");
+
+            values
+                .iter()
+                .map(|value| match value {
+                    toml::Value::Array(_) | toml::Value::Table(_) => panic!(
+                        "Invalid config file value for {} (expected a list of strings)",
+                        key.to_lowercase()
+                    ),
+                    value => toml_value_to_config_str(key, value),
+                })
+                .collect::<Vec<String>>()
+                .join(separator)
+        }
+        toml::Value::Table(_) => panic!(
+            "Invalid config file value for {} (tables aren't supported)",
+            key.to_lowercase()
+        ),
+        value => value.to_string(),
+    }
+}
+
+// Header names are RFC 9110 tokens
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
 fn check_file_path(path: &str) -> Result<String, PathError> {
     check_path(path, false)
 }
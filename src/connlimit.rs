@@ -0,0 +1,63 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+pub struct ConnectionLimiter {
+    max_per_ip: u32,
+    max_total: u32,
+    total: AtomicU32,
+    per_ip: DashMap<IpAddr, AtomicU32>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: u32, max_total: u32) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_per_ip: max_per_ip,
+            max_total: max_total,
+            total: AtomicU32::new(0),
+            per_ip: DashMap::new(),
+        }
+    }
+
+    // Returns None (without counting the connection) if either limit is reached (a limit of 0 disables it)
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let total = self.total.fetch_add(1, Ordering::SeqCst);
+        if self.max_total != 0 && total >= self.max_total {
+            self.total.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        // Holding the entry keeps the per-IP check and increment atomic
+        let entry = self.per_ip.entry(ip).or_insert_with(|| AtomicU32::new(0));
+        if self.max_per_ip != 0 && entry.load(Ordering::SeqCst) >= self.max_per_ip {
+            drop(entry);
+            self.total.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        entry.fetch_add(1, Ordering::SeqCst);
+
+        Some(ConnectionGuard {
+            limiter: self.clone(),
+            ip: ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        self.per_ip
+            .remove_if(&ip, |_, count| count.fetch_sub(1, Ordering::SeqCst) <= 1);
+        self.total.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Releases the connection when dropped, so it is released even if the connection's task panics
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
@@ -1,37 +1,41 @@
 use std::{
-    cmp::Reverse,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
     ffi::{OsStr, OsString},
     fs::{self, Metadata},
+    hash::Hasher,
     path::PathBuf,
-    sync::Mutex,
-    time::SystemTime,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
-    config::Config,
+    config::{Config, PostsSortOrder},
+    connlimit::ConnectionLimiter,
+    feed::generate_atom_feed,
+    files::{json_value_merge, parse_front_matter},
+    metrics::Metrics,
     protocol::Protocol,
+    ratelimit::RateLimiter,
+    redirects::{load_redirect_rules, RedirectRule},
     templates::{initialize_handlebars, DEFAULT_BLANK_PARTIAL_NAME},
 };
 use cached::stores::ExpiringSizedCache;
 use chrono::{DateTime, Utc};
-use gray_matter::{engine::YAML, Matter, Pod};
+use gray_matter::Pod;
 use handlebars::Handlebars;
-use log::{debug, error};
+use log::{debug, error, warn};
 use serde::Serialize;
 use serde_json::json;
+use twox_hash::XxHash64;
 use walkdir::WalkDir;
 
-const MAX_FS_CACHE_ENTRIES: usize = 512;
-const MAX_FS_CACHE_LONG_TTL_MS: u64 = 14_400_000;
-
-const MAX_FS_CACHE_SHORT_TTL_EXTENSIONS: &[&str] = &["hbs", "html", "gmi", "md", "json"];
-const MAX_FS_CACHE_SHORT_TTL_MS: u64 = 10_000;
-
-const MAX_DATA_CACHE_ENTRIES: usize = 512;
 const MAX_DATA_CACHE_TTL_MS: u64 = 10_000;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+// In order of precedence for files with the same name
+const DATA_FILE_EXTENSIONS: &[&str] = &["json", "toml", "md"];
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct PageMetadata {
     path: String,
     protocol: Protocol,
@@ -40,15 +44,112 @@ pub struct PageMetadata {
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
     is_post: bool,
+    tags: Vec<String>,
+    noindex: bool,
+    author: Option<String>,
+    language: Option<String>,
+}
+
+impl PageMetadata {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn created_at(&self) -> &DateTime<Utc> {
+        &self.created_at
+    }
+
+    pub fn updated_at(&self) -> &DateTime<Utc> {
+        &self.updated_at
+    }
+
+    pub fn noindex(&self) -> bool {
+        self.noindex
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+}
+
+// Ordered by date, then title and path so posts with the same date have a stable order
+impl Ord for PageMetadata {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.created_at
+            .cmp(&other.created_at)
+            .then_with(|| self.title.cmp(&other.title))
+            .then_with(|| self.path.cmp(&other.path))
+    }
+}
+
+impl PartialOrd for PageMetadata {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for PageMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PageMetadata {}
+
+// Ties (same date or title) are always broken by title/date ascending, then path
+fn sort_posts(posts: &mut [PageMetadata], sort_order: PostsSortOrder) {
+    match sort_order {
+        PostsSortOrder::DateAsc => posts.sort(),
+        PostsSortOrder::DateDesc => posts.sort_by(|a, b| {
+            b.created_at
+                .cmp(&a.created_at)
+                .then_with(|| a.title.cmp(&b.title))
+                .then_with(|| a.path.cmp(&b.path))
+        }),
+        PostsSortOrder::TitleAsc => {
+            posts.sort_by(|a, b| a.title.cmp(&b.title).then_with(|| a.cmp(b)))
+        }
+        PostsSortOrder::TitleDesc => {
+            posts.sort_by(|a, b| b.title.cmp(&a.title).then_with(|| a.cmp(b)))
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct CachedFile {
     data: Vec<u8>,
     metadata: Metadata,
+    etag: String,
 }
 
 impl CachedFile {
+    fn new(data: Vec<u8>, metadata: Metadata) -> CachedFile {
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(&data);
+
+        CachedFile {
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            data: data,
+            metadata: metadata,
+        }
+    }
+
     pub fn data(&self) -> &Vec<u8> {
         &self.data
     }
@@ -56,13 +157,30 @@ impl CachedFile {
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
+
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    // The content hash from the ETag without its quotes
+    pub fn content_hash(&self) -> &str {
+        self.etag.trim_matches('"')
+    }
 }
 
 pub struct ServerContext {
     config: Config,
-    handlebars: Mutex<Handlebars<'static>>,
+    handlebars: RwLock<Handlebars<'static>>,
+    handlebars_templates_registered_at: Mutex<Option<Instant>>,
+    // The file each partial was last registered from and its modified time at the time
+    registered_partials: Mutex<HashMap<String, (PathBuf, SystemTime)>>,
     fs_cache: Mutex<ExpiringSizedCache<OsString, CachedFile>>,
     data_cache: Mutex<ExpiringSizedCache<OsString, serde_json::Value>>,
+    rate_limiter: RateLimiter,
+    connection_limiter: Arc<ConnectionLimiter>,
+    metrics: Metrics,
+    page_metadata_cache: Mutex<Option<(Instant, Vec<PageMetadata>)>>,
+    redirect_rules: Vec<RedirectRule>,
 }
 
 #[derive(Debug)]
@@ -70,31 +188,102 @@ pub enum DataReadErr {
     JsonError(serde_json::Error),
     Utf8Error(std::str::Utf8Error),
     IoError(std::io::Error),
+    TomlError(toml::de::Error),
+}
+
+// Markdown is kept as a string (without its front matter) for the markdown helper to render
+fn parse_data_str(data_str: &str, extension: &str) -> Result<serde_json::Value, DataReadErr> {
+    match extension {
+        "toml" => toml::from_str::<toml::Value>(data_str)
+            .map(toml_to_json)
+            .map_err(DataReadErr::TomlError),
+        "md" => Ok(serde_json::Value::String(
+            parse_front_matter(data_str).content,
+        )),
+        _ => serde_json::from_str::<serde_json::Value>(data_str).map_err(DataReadErr::JsonError),
+    }
+}
+
+// Datetimes become RFC 3339 strings as JSON has no equivalent
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(string) => serde_json::Value::String(string),
+        toml::Value::Integer(integer) => serde_json::Value::from(integer),
+        toml::Value::Float(float) => serde_json::Number::from_f64(float)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        toml::Value::Boolean(boolean) => serde_json::Value::Bool(boolean),
+        toml::Value::Datetime(datetime) => serde_json::Value::String(datetime.to_string()),
+        toml::Value::Array(array) => {
+            serde_json::Value::Array(array.into_iter().map(toml_to_json).collect())
+        }
+        toml::Value::Table(table) => serde_json::Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
 }
 
 impl ServerContext {
-    pub fn new_with_config(config: Config) -> ServerContext {
-        let mut handlebars = Handlebars::new();
-        initialize_handlebars(&mut handlebars);
-
-        ServerContext {
-            config: config,
-            handlebars: Mutex::new(handlebars),
-            fs_cache: Mutex::new(ExpiringSizedCache::with_capacity(
-                MAX_FS_CACHE_LONG_TTL_MS,
-                MAX_FS_CACHE_ENTRIES,
-            )),
-            data_cache: Mutex::new(ExpiringSizedCache::with_capacity(
+    pub fn new_with_config(config: Config) -> Arc<ServerContext> {
+        // Helpers that need to read files hold a weak reference back to the context
+        Arc::new_cyclic(|server_context| {
+            let mut handlebars = Handlebars::new();
+            initialize_handlebars(&mut handlebars, server_context.clone());
+
+            let fs_cache = ExpiringSizedCache::with_capacity(
+                config.fs_cache_long_ttl_ms(),
+                config.max_fs_cache_entries(),
+            );
+            let data_cache = ExpiringSizedCache::with_capacity(
                 MAX_DATA_CACHE_TTL_MS,
-                MAX_DATA_CACHE_ENTRIES,
-            )),
-        }
+                config.max_data_cache_entries(),
+            );
+
+            ServerContext {
+                rate_limiter: RateLimiter::new(
+                    config.rate_limit_requests_per_second(),
+                    config.rate_limit_burst(),
+                ),
+                connection_limiter: Arc::new(ConnectionLimiter::new(
+                    config.max_connections_per_ip(),
+                    config.max_total_connections(),
+                )),
+                metrics: Metrics::new(),
+                redirect_rules: load_redirect_rules(config.redirects_data_file()),
+                config: config,
+                handlebars: RwLock::new(handlebars),
+                handlebars_templates_registered_at: Mutex::new(None),
+                registered_partials: Mutex::new(HashMap::new()),
+                page_metadata_cache: Mutex::new(None),
+                fs_cache: Mutex::new(fs_cache),
+                data_cache: Mutex::new(data_cache),
+            }
+        })
     }
 
     pub fn config(&self) -> &Config {
         &self.config
     }
 
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn connection_limiter(&self) -> &Arc<ConnectionLimiter> {
+        &self.connection_limiter
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn redirect_rules(&self) -> &[RedirectRule] {
+        &self.redirect_rules
+    }
+
     pub fn handlebars_render_template<T>(
         &self,
         template_string: &str,
@@ -105,60 +294,121 @@ impl ServerContext {
     {
         self.register_handlebars_templates();
         self.handlebars
-            .lock()
+            .read()
             .unwrap()
             .render_template(template_string, &data)
     }
 
+    // Renders a registered partial by name
+    pub fn handlebars_render_partial<T>(
+        &self,
+        partial_name: &str,
+        data: T,
+    ) -> Result<std::string::String, handlebars::RenderError>
+    where
+        T: Serialize,
+    {
+        self.register_handlebars_templates();
+        self.handlebars.read().unwrap().render(partial_name, &data)
+    }
+
+    // Re-registers partials at most once per short fs cache TTL
     fn register_handlebars_templates(&self) {
-        for entry in WalkDir::new(self.config().partials_path())
-            .follow_links(false)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let path_buf = entry.into_path();
-            let path_str = path_buf.to_str().unwrap();
-            if path_str.ends_with(".hbs") {
-                let partial_name = path_str
-                    .strip_prefix(&format!("{}/", self.config().partials_path()))
-                    .unwrap()
-                    .strip_suffix(".hbs")
-                    .unwrap()
-                    .to_string();
+        // Held until registration finishes so concurrent renders don't all re-walk the partials
+        let mut registered_at = self.handlebars_templates_registered_at.lock().unwrap();
+        if let Some(registered_at) = *registered_at {
+            if registered_at.elapsed() < Duration::from_millis(self.config.fs_cache_short_ttl_ms())
+            {
+                return;
+            }
+        }
 
-                match self.fs_read(path_buf) {
-                    Ok(file) => match std::str::from_utf8(&file.data()) {
-                        Ok(value) => {
-                            let mut handlebars = self.handlebars.lock().unwrap();
-                            match handlebars.register_template_string(&partial_name, value) {
-                                Ok(_) => {}
-                                Err(err) => error!(
-                                    "ERROR registering handlebar partial {}: {}",
-                                    partial_name, err
-                                ),
-                            }
+        // Later folders override partials with the same name
+        let mut partial_paths = HashMap::<String, (PathBuf, Option<SystemTime>)>::new();
+        for partials_path in self.config().partials_paths() {
+            for entry in WalkDir::new(partials_path)
+                .follow_links(false)
+                .into_iter()
+                .filter_map(|e| e.ok())
+            {
+                let modified = entry
+                    .metadata()
+                    .ok()
+                    .and_then(|metadata| metadata.modified().ok());
+                let path_buf = entry.into_path();
+                let path_str = path_buf.to_str().unwrap();
+                if path_str.ends_with(".hbs") {
+                    let partial_name = path_str
+                        .strip_prefix(&format!("{}/", partials_path))
+                        .unwrap()
+                        .strip_suffix(".hbs")
+                        .unwrap()
+                        .to_string();
+
+                    partial_paths.insert(partial_name, (path_buf, modified));
+                }
+            }
+        }
+
+        let template_cache_enabled = self.config().template_cache_enabled();
+        let mut registered_partials = self.registered_partials.lock().unwrap();
+        let mut partials = Vec::<(String, String)>::new();
+
+        for (partial_name, (path_buf, modified)) in partial_paths {
+            // Partials from the same file that hasn't been modified since are already registered
+            if template_cache_enabled {
+                if let (Some(modified), Some((registered_path_buf, registered_modified))) =
+                    (modified, registered_partials.get(&partial_name))
+                {
+                    if *registered_path_buf == path_buf && modified <= *registered_modified {
+                        continue;
+                    }
+                }
+            }
+
+            match self.fs_read(path_buf.clone()) {
+                Ok(file) => match std::str::from_utf8(&file.data()) {
+                    Ok(value) => {
+                        // The time from the (possibly cached) file read, so a stale read is retried
+                        if let Ok(modified) = file.metadata().modified() {
+                            registered_partials.insert(partial_name.clone(), (path_buf, modified));
                         }
-                        Err(err) => error!(
-                            "ERROR loading handlebar partial {} as UTF-8: {}",
-                            partial_name, err
-                        ),
-                    },
-                    Err(err) => {
-                        error!(
-                            "ERROR loading handlebar partial file {}: {}",
-                            partial_name, err
-                        )
+                        partials.push((partial_name, value.to_string()))
                     }
+                    Err(err) => error!(
+                        "ERROR loading handlebar partial {} as UTF-8: {}",
+                        partial_name, err
+                    ),
+                },
+                Err(err) => {
+                    error!(
+                        "ERROR loading handlebar partial file {}: {}",
+                        partial_name, err
+                    )
                 }
             }
         }
 
+        // Only block renders for the registration itself, not the filesystem walk
+        let mut handlebars = self.handlebars.write().unwrap();
+
+        for (partial_name, value) in partials {
+            match handlebars.register_template_string(&partial_name, value) {
+                Ok(_) => {}
+                Err(err) => error!(
+                    "ERROR registering handlebar partial {}: {}",
+                    partial_name, err
+                ),
+            }
+        }
+
         // Register special "blank" partial
-        let mut handlebars = self.handlebars.lock().unwrap();
         match handlebars.register_template_string(DEFAULT_BLANK_PARTIAL_NAME, "") {
             Ok(_) => {}
             Err(err) => error!("ERROR registering default handlebar partial blank: {}", err),
         }
+
+        *registered_at = Some(Instant::now());
     }
 
     pub fn fs_read(&self, path_buf: PathBuf) -> Result<CachedFile, std::io::Error> {
@@ -173,22 +423,30 @@ impl ServerContext {
             }
             None => match (fs::read(path_buf.clone()), fs::metadata(path_buf.clone())) {
                 (Ok(data), Ok(metadata)) => {
-                    let cached_file = CachedFile {
-                        data: data.clone(),
-                        metadata: metadata.clone(),
-                    };
-                    if MAX_FS_CACHE_SHORT_TTL_EXTENSIONS.contains(
-                        &cloned_path_buf
-                            .extension()
-                            .unwrap_or(OsStr::new(""))
-                            .to_str()
-                            .unwrap_or(""),
-                    ) {
+                    let cached_file = CachedFile::new(data, metadata);
+
+                    // A changed template may have changed front matter
+                    if cloned_path_buf.extension() == Some(OsStr::new("hbs")) {
+                        *self.page_metadata_cache.lock().unwrap() = None;
+                    }
+
+                    let extension = cloned_path_buf
+                        .extension()
+                        .unwrap_or(OsStr::new(""))
+                        .to_str()
+                        .unwrap_or("");
+
+                    if self
+                        .config
+                        .fs_cache_short_ttl_extensions()
+                        .iter()
+                        .any(|short_ttl_extension| short_ttl_extension == extension)
+                    {
                         debug!("fs cache miss (short ttl): {:?}", cache_key);
                         match fs_cache.insert_ttl(
                             cache_key.clone(),
                             cached_file.clone(),
-                            MAX_FS_CACHE_SHORT_TTL_MS,
+                            self.config.fs_cache_short_ttl_ms(),
                         ) {
                             Ok(_) => {}
                             Err(err) => error!(
@@ -214,21 +472,110 @@ impl ServerContext {
         }
     }
 
-    pub fn get_sorted_posts_for_protocol(&self, protocol: Protocol) -> Vec<PageMetadata> {
+    // Reads a file relative to the public root requests for the protocol and hostname are served
+    // from, refusing anything that canonicalizes outside of it
+    pub fn fs_read_public_root_path(
+        &self,
+        protocol: Protocol,
+        hostname: &str,
+        path: &str,
+    ) -> Result<CachedFile, std::io::Error> {
+        let public_root_path = self.config().public_root_path_for(protocol, hostname);
+        let path_buf = PathBuf::from(format!(
+            "{}/{}",
+            public_root_path,
+            path.trim_start_matches('/')
+        ))
+        .canonicalize()?;
+
+        if !path_buf.starts_with(format!("{}/", public_root_path)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "path not in public root dir",
+            ));
+        }
+
+        self.fs_read(path_buf)
+    }
+
+    pub fn get_sorted_posts_for_protocol(
+        &self,
+        protocol: Protocol,
+        sort_order: PostsSortOrder,
+    ) -> Vec<PageMetadata> {
         let mut posts = self
             .get_page_metadata()
             .into_iter()
             .filter(|pm| pm.is_post && pm.protocol == protocol)
             .collect::<Vec<PageMetadata>>();
 
-        posts.sort_by_key(|pm| Reverse(pm.created_at));
+        sort_posts(&mut posts, sort_order);
 
         posts
     }
 
-    // TODO: make this function less insane
+    // Deduplicated and sorted tags of every post, for either protocol
+    pub fn get_all_tags(&self) -> Vec<String> {
+        let mut tags = self
+            .get_page_metadata()
+            .into_iter()
+            .filter(|pm| pm.is_post)
+            .flat_map(|pm| pm.tags)
+            .collect::<Vec<String>>();
+
+        tags.sort();
+        tags.dedup();
+
+        tags
+    }
+
+    pub fn get_atom_feed_xml(&self, protocol: Protocol) -> String {
+        generate_atom_feed(self, protocol)
+    }
+
     pub fn get_page_metadata(&self) -> Vec<PageMetadata> {
-        WalkDir::new(self.config().public_root_path())
+        if let Some((cached_at, page_metadata)) = &*self.page_metadata_cache.lock().unwrap() {
+            if cached_at.elapsed() < Duration::from_millis(self.config.fs_cache_short_ttl_ms()) {
+                debug!("page metadata cache hit");
+                return page_metadata.clone();
+            }
+        }
+
+        // Not holding the lock while scanning as fs_read can invalidate the cache
+        let page_metadata = self.scan_page_metadata();
+        debug!("page metadata cache miss");
+        *self.page_metadata_cache.lock().unwrap() = Some((Instant::now(), page_metadata.clone()));
+
+        page_metadata
+    }
+
+    // Protocols with their own public root only list the pages in it
+    fn scan_page_metadata(&self) -> Vec<PageMetadata> {
+        let https_public_root_path = self.config().public_root_path_for_protocol(Protocol::Https);
+        let gemini_public_root_path = self
+            .config()
+            .public_root_path_for_protocol(Protocol::Gemini);
+
+        if https_public_root_path == gemini_public_root_path {
+            return self.scan_page_metadata_in(https_public_root_path);
+        }
+
+        [
+            (Protocol::Https, https_public_root_path),
+            (Protocol::Gemini, gemini_public_root_path),
+        ]
+        .into_iter()
+        .flat_map(|(protocol, public_root_path)| {
+            self.scan_page_metadata_in(public_root_path)
+                .into_iter()
+                .filter(move |page_metadata| page_metadata.protocol == protocol)
+        })
+        .collect()
+    }
+
+    // TODO: make this function less insane
+    fn scan_page_metadata_in(&self, public_root_path: &str) -> Vec<PageMetadata> {
+        WalkDir::new(public_root_path)
             .follow_links(false)
             .into_iter()
             .flat_map(|e| match e {
@@ -239,9 +586,7 @@ impl ServerContext {
                             match self.fs_read(path_buf) {
                                 Ok(file) => match std::str::from_utf8(&file.data()) {
                                     Ok(data_str) => {
-                                        let matter = Matter::<YAML>::new();
-                                        if let Ok(data) = matter
-                                            .parse(data_str)
+                                        if let Ok(data) = parse_front_matter(data_str)
                                             .data
                                             .unwrap_or(Pod::Null)
                                             .as_hashmap()
@@ -321,6 +666,40 @@ impl ServerContext {
                                                     .ok()
                                                     .unwrap_or(false);
 
+                                                let author = data
+                                                    .get("author")
+                                                    .unwrap_or(&Pod::Null)
+                                                    .as_string()
+                                                    .ok();
+
+                                                let language = data
+                                                    .get("lang")
+                                                    .or(data.get("language"))
+                                                    .unwrap_or(&Pod::Null)
+                                                    .as_string()
+                                                    .ok();
+
+                                                let noindex = data
+                                                    .get("noindex")
+                                                    .unwrap_or(&Pod::Null)
+                                                    .as_bool()
+                                                    .ok()
+                                                    .unwrap_or(false);
+
+                                                let tags =
+                                                    match data.get("tags").unwrap_or(&Pod::Null) {
+                                                        Pod::Array(tags) => tags
+                                                            .iter()
+                                                            .filter_map(|tag| tag.as_string().ok())
+                                                            .collect::<Vec<String>>(),
+                                                        Pod::String(tags) => tags
+                                                            .split(',')
+                                                            .map(|tag| tag.trim().to_string())
+                                                            .filter(|tag| !tag.is_empty())
+                                                            .collect::<Vec<String>>(),
+                                                        _ => Vec::new(),
+                                                    };
+
                                                 // todo better protocol handling here
                                                 let (protocols, uri_path) = if let Some(uri_path) =
                                                     path_str.strip_suffix(".html.hbs")
@@ -348,9 +727,7 @@ impl ServerContext {
                                                     .ends_with("/index")
                                                 {
                                                     let base = uri_path
-                                                        .strip_prefix(
-                                                            self.config().public_root_path(),
-                                                        )
+                                                        .strip_prefix(public_root_path)
                                                         .unwrap()
                                                         .to_string();
                                                     match base.strip_suffix("/index") {
@@ -360,9 +737,7 @@ impl ServerContext {
                                                     }
                                                 } else {
                                                     uri_path
-                                                        .strip_prefix(
-                                                            self.config().public_root_path(),
-                                                        )
+                                                        .strip_prefix(public_root_path)
                                                         .unwrap()
                                                         .to_string()
                                                 };
@@ -381,6 +756,10 @@ impl ServerContext {
                                                         created_at: created_at,
                                                         updated_at: updated_at,
                                                         is_post: is_post,
+                                                        tags: tags.clone(),
+                                                        noindex: noindex,
+                                                        author: author.clone(),
+                                                        language: language.clone(),
                                                     })
                                                     .collect::<Vec<PageMetadata>>()
                                             } else {
@@ -407,6 +786,9 @@ impl ServerContext {
     pub fn get_data(&self) -> serde_json::Value {
         let mut data = json!({});
 
+        // Data keys to their files, so files with the same name can be resolved without more stats
+        let mut data_files = BTreeMap::<String, Vec<(usize, PathBuf)>>::new();
+
         for entry in WalkDir::new(self.config().data_path())
             .follow_links(false)
             .into_iter()
@@ -414,21 +796,46 @@ impl ServerContext {
         {
             let path_buf = entry.into_path();
             let path_str = path_buf.to_str().unwrap();
-            if path_str.ends_with(".json") {
+            let extension = path_buf
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("");
+
+            if let Some(precedence) = DATA_FILE_EXTENSIONS
+                .iter()
+                .position(|data_file_extension| *data_file_extension == extension)
+            {
                 let data_key = path_str
-                    .strip_prefix(&format!("{}/", self.config().data_path()))
+                    .strip_suffix(&format!(".{}", extension))
                     .unwrap()
-                    .strip_suffix(".json")
+                    .strip_prefix(&format!("{}/", self.config().data_path()))
                     .unwrap()
                     .to_string();
 
-                match self.data_read(path_buf) {
-                    Ok(value) => {
-                        data.as_object_mut().unwrap().insert(data_key, value);
-                    }
-                    Err(err) => {
-                        error!("ERROR reading data JSON file {}: {:?}", data_key, err)
-                    }
+                data_files
+                    .entry(data_key)
+                    .or_default()
+                    .push((precedence, path_buf));
+            }
+        }
+
+        for (data_key, mut paths) in data_files {
+            paths.sort_by_key(|(precedence, _)| *precedence);
+            let mut paths = paths.into_iter().map(|(_, path_buf)| path_buf);
+            let path_buf = paths.next().unwrap();
+            let shadowed_path_bufs = paths.collect::<Vec<PathBuf>>();
+
+            match self.data_read(path_buf, &shadowed_path_bufs) {
+                Ok(value) => {
+                    // i.e. blog/posts.json is available as data.blog.posts
+                    let nested_value = data_key
+                        .split('/')
+                        .rev()
+                        .fold(value, |value, key| json!({ key: value }));
+                    json_value_merge(&mut data, nested_value);
+                }
+                Err(err) => {
+                    error!("ERROR reading data file {}: {:?}", data_key, err)
                 }
             }
         }
@@ -436,7 +843,12 @@ impl ServerContext {
         data
     }
 
-    fn data_read(&self, path_buf: PathBuf) -> Result<serde_json::Value, DataReadErr> {
+    // Files shadowed by path_buf are only warned about when it is read, not on every cache hit
+    fn data_read(
+        &self,
+        path_buf: PathBuf,
+        shadowed_path_bufs: &[PathBuf],
+    ) -> Result<serde_json::Value, DataReadErr> {
         let cloned_path_buf = path_buf.clone();
         let cache_key = cloned_path_buf.as_os_str().to_os_string();
         let mut data_cache = self.data_cache.lock().unwrap();
@@ -449,8 +861,18 @@ impl ServerContext {
             None => match fs::read(path_buf) {
                 Ok(data) => {
                     debug!("data cache miss: {:?}", cache_key);
+                    for shadowed_path_buf in shadowed_path_bufs {
+                        warn!(
+                            "data file {:?} ignored as {:?} takes precedence",
+                            shadowed_path_buf, cloned_path_buf
+                        );
+                    }
+                    let extension = cloned_path_buf
+                        .extension()
+                        .and_then(|extension| extension.to_str())
+                        .unwrap_or("");
                     match std::str::from_utf8(&data) {
-                        Ok(json_str) => match serde_json::from_str::<serde_json::Value>(json_str) {
+                        Ok(data_str) => match parse_data_str(data_str, extension) {
                             Ok(json) => {
                                 match data_cache.insert(cache_key.clone(), json.clone()) {
                                     Ok(_) => {}
@@ -461,7 +883,7 @@ impl ServerContext {
                                 }
                                 Ok(json)
                             }
-                            Err(err) => Err(DataReadErr::JsonError(err)),
+                            Err(err) => Err(err),
                         },
                         Err(err) => Err(DataReadErr::Utf8Error(err)),
                     }
@@ -471,3 +893,102 @@ impl ServerContext {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(path: &str, title: &str, created_at: &str) -> PageMetadata {
+        let created_at = DateTime::parse_from_rfc3339(created_at)
+            .unwrap()
+            .with_timezone(&Utc);
+
+        PageMetadata {
+            path: path.to_string(),
+            protocol: Protocol::Https,
+            title: title.to_string(),
+            description: None,
+            created_at: created_at,
+            updated_at: created_at,
+            is_post: true,
+            tags: Vec::new(),
+            noindex: false,
+            author: None,
+            language: None,
+        }
+    }
+
+    // /a and /b have the same date, /b and /c have the same title
+    fn sorted_paths(sort_order: PostsSortOrder) -> Vec<String> {
+        let mut posts = vec![
+            post("/b", "Beta", "2024-01-02T00:00:00Z"),
+            post("/d", "Gamma", "2024-01-03T00:00:00Z"),
+            post("/a", "Alpha", "2024-01-02T00:00:00Z"),
+            post("/c", "Beta", "2024-01-01T00:00:00Z"),
+        ];
+
+        sort_posts(&mut posts, sort_order);
+        posts.iter().map(|post| post.path().to_string()).collect()
+    }
+
+    #[test]
+    fn sort_posts_by_date_breaks_ties_by_title() {
+        assert_eq!(
+            sorted_paths(PostsSortOrder::DateAsc),
+            ["/c", "/a", "/b", "/d"]
+        );
+        assert_eq!(
+            sorted_paths(PostsSortOrder::DateDesc),
+            ["/d", "/a", "/b", "/c"]
+        );
+    }
+
+    #[test]
+    fn sort_posts_by_title_breaks_ties_by_date() {
+        assert_eq!(
+            sorted_paths(PostsSortOrder::TitleAsc),
+            ["/a", "/c", "/b", "/d"]
+        );
+        assert_eq!(
+            sorted_paths(PostsSortOrder::TitleDesc),
+            ["/d", "/c", "/b", "/a"]
+        );
+    }
+
+    #[test]
+    fn sort_posts_breaks_full_ties_by_path() {
+        let mut posts = vec![
+            post("/z", "Same", "2024-01-01T00:00:00Z"),
+            post("/y", "Same", "2024-01-01T00:00:00Z"),
+        ];
+
+        for sort_order in [
+            PostsSortOrder::DateAsc,
+            PostsSortOrder::DateDesc,
+            PostsSortOrder::TitleAsc,
+            PostsSortOrder::TitleDesc,
+        ] {
+            sort_posts(&mut posts, sort_order);
+            assert_eq!(posts[0].path(), "/y");
+        }
+    }
+
+    #[test]
+    fn page_metadata_round_trips_through_json() {
+        let mut page_metadata = post("/posts/hello", "Hello", "2024-01-02T03:04:05Z");
+        page_metadata.protocol = Protocol::Gemini;
+        page_metadata.description = Some("A first post".to_string());
+
+        let value = serde_json::to_value(&page_metadata).unwrap();
+        assert_eq!(value["path"], "/posts/hello");
+        assert_eq!(value["protocol"], "Gemini");
+        assert_eq!(value["title"], "Hello");
+        assert_eq!(value["description"], "A first post");
+        assert_eq!(value["created_at"], "2024-01-02T03:04:05Z");
+        assert_eq!(value["is_post"], true);
+
+        let deserialized: PageMetadata = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(deserialized, page_metadata);
+        assert_eq!(serde_json::to_value(&deserialized).unwrap(), value);
+    }
+}
@@ -0,0 +1,156 @@
+use chrono::Utc;
+
+use crate::config::PostsSortOrder;
+use crate::context::{PageMetadata, ServerContext};
+use crate::protocol::Protocol;
+
+pub const FEED_MAX_ENTRIES: usize = 20;
+
+pub const GEMSUB_FEED_PATH: &str = "/tinylog.gmi";
+
+pub const ATOM_FEED_PATH: &str = "/feed.xml";
+
+pub const GEMINI_FEED_PATH: &str = "/feed.gmi";
+
+pub const ATOM_FEED_MEDIA_TYPE: &str = "application/atom+xml; charset=utf-8";
+
+pub const SITEMAP_PATH: &str = "/sitemap.xml";
+
+pub const SITEMAP_MEDIA_TYPE: &str = "application/xml";
+
+pub const SITEMAP_CACHE_MAX_AGE_SECONDS: u32 = 3600;
+
+fn xml_escape(str: &str) -> String {
+    str.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Atom 1.0 (RFC 4287) feed of posts with links using DEFAULT_HOSTNAME
+pub fn generate_atom_feed(server_context: &ServerContext, protocol: Protocol) -> String {
+    let base_uri = match protocol {
+        Protocol::Gemini => format!("gemini://{}", server_context.config().default_hostname()),
+        Protocol::Https => format!("https://{}", server_context.config().default_hostname()),
+    };
+
+    let posts = server_context.get_sorted_posts_for_protocol(protocol, PostsSortOrder::DateDesc);
+    let posts = posts
+        .iter()
+        .take(FEED_MAX_ENTRIES)
+        .collect::<Vec<&PageMetadata>>();
+
+    let feed_updated_at = posts
+        .iter()
+        .map(|post| *post.updated_at())
+        .max()
+        .unwrap_or(Utc::now());
+
+    let mut feed = String::new();
+    feed.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str(&format!(
+        "  <title>{}</title>\n",
+        xml_escape(server_context.config().default_hostname())
+    ));
+    feed.push_str(&format!("  <id>{}/</id>\n", xml_escape(&base_uri)));
+    feed.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}{}\"/>\n",
+        xml_escape(&base_uri),
+        ATOM_FEED_PATH
+    ));
+    feed.push_str(&format!("  <link href=\"{}/\"/>\n", xml_escape(&base_uri)));
+    feed.push_str(&format!(
+        "  <updated>{}</updated>\n",
+        feed_updated_at.to_rfc3339()
+    ));
+
+    for post in posts {
+        let post_uri = xml_escape(&format!("{}{}", base_uri, post.path()));
+
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!(
+            "    <title>{}</title>\n",
+            xml_escape(post.title())
+        ));
+        feed.push_str(&format!("    <id>{}</id>\n", post_uri));
+        feed.push_str(&format!("    <link href=\"{}\"/>\n", post_uri));
+        feed.push_str(&format!(
+            "    <published>{}</published>\n",
+            post.created_at().to_rfc3339()
+        ));
+        feed.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            post.updated_at().to_rfc3339()
+        ));
+        if let Some(author) = post.author() {
+            feed.push_str(&format!(
+                "    <author><name>{}</name></author>\n",
+                xml_escape(author)
+            ));
+        }
+        if let Some(description) = post.description() {
+            feed.push_str(&format!(
+                "    <summary>{}</summary>\n",
+                xml_escape(description)
+            ));
+        }
+        feed.push_str("  </entry>\n");
+    }
+
+    feed.push_str("</feed>\n");
+
+    feed
+}
+
+// Sitemap (https://www.sitemaps.org/protocol.html) of all HTTPS pages (except noindex ones) with
+// links using DEFAULT_HOSTNAME
+pub fn generate_sitemap(server_context: &ServerContext) -> String {
+    let base_uri = format!("https://{}", server_context.config().default_hostname());
+
+    let mut sitemap = String::new();
+    sitemap.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    sitemap.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+
+    for page in server_context
+        .get_page_metadata()
+        .iter()
+        .filter(|pm| pm.protocol() == Protocol::Https && !pm.noindex())
+    {
+        sitemap.push_str("  <url>\n");
+        sitemap.push_str(&format!(
+            "    <loc>{}</loc>\n",
+            xml_escape(&format!("{}{}", base_uri, page.path()))
+        ));
+        sitemap.push_str(&format!(
+            "    <lastmod>{}</lastmod>\n",
+            page.updated_at().format("%Y-%m-%d")
+        ));
+        sitemap.push_str("  </url>\n");
+    }
+
+    sitemap.push_str("</urlset>\n");
+
+    sitemap
+}
+
+// Gemini subscription feed (https://geminiprotocol.net/docs/companion/subscription.gmi) as used by aggregators like Antenna
+pub fn generate_gemsub_feed(server_context: &ServerContext) -> String {
+    let mut feed = format!("# {}\n", server_context.config().default_hostname());
+
+    for post in server_context
+        .get_sorted_posts_for_protocol(Protocol::Gemini, PostsSortOrder::DateDesc)
+        .iter()
+        .take(FEED_MAX_ENTRIES)
+    {
+        feed.push_str(&format!(
+            "\n### {}\n=> {} {}\n",
+            post.created_at().format("%Y-%m-%d"),
+            post.path(),
+            post.title()
+        ));
+    }
+
+    feed
+}
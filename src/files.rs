@@ -2,12 +2,14 @@ use chrono::{DateTime, Utc};
 use log::{error, info};
 use serde_json::json;
 
+use crate::protocol::Protocol;
 use crate::request::Request;
 use crate::response::{Response, Status};
-use crate::templates::render_response_body_for_request;
-use gray_matter::engine::YAML;
-use gray_matter::Matter;
-use std::path::PathBuf;
+use crate::templates::{render_layout_for_request, render_response_body_for_request};
+use gray_matter::engine::{TOML, YAML};
+use gray_matter::{Matter, ParsedEntity};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
 pub fn try_load_file_for_path(path: &str, request: &mut Request) -> Result<Response, Status> {
     let mut try_path = path.to_string();
@@ -15,6 +17,29 @@ pub fn try_load_file_for_path(path: &str, request: &mut Request) -> Result<Respo
     if !try_path.ends_with(".hbs") {
         // Try exact match
         match try_load_file(&try_path, request) {
+            // Markdown is rendered later, but its front matter needs to be in meta first
+            Ok(response) if try_path.ends_with(".md") => {
+                return match String::from_utf8(response.body().to_vec()) {
+                    Ok(body) => Ok(Response::new(
+                        *response.status(),
+                        response.media_type(),
+                        extract_front_matter(&body, request).as_bytes(),
+                        response.cacheable(),
+                    )),
+                    Err(err) => {
+                        error!(
+                            "[{}] [{}] [{}] [{}] Unicode error reading {} (valid up to {})",
+                            request.protocol(),
+                            request.client_ip(),
+                            request.client_certificate_details(),
+                            request.path(),
+                            path,
+                            err.utf8_error().valid_up_to()
+                        );
+                        Err(Status::OtherServerError)
+                    }
+                }
+            }
             Ok(response) => return Ok(response),
             Err(status) => match status {
                 Status::NotFound => {}
@@ -28,28 +53,25 @@ pub fn try_load_file_for_path(path: &str, request: &mut Request) -> Result<Respo
         try_path.push_str(".hbs");
     }
 
-    fn json_value_merge(dst: &mut serde_json::Value, src: serde_json::Value) {
-        match (dst, src) {
-            (dst @ &mut serde_json::Value::Object(_), serde_json::Value::Object(src)) => {
-                let dst = dst.as_object_mut().unwrap();
-                for (k, v) in src {
-                    json_value_merge(dst.entry(k).or_insert(serde_json::Value::Null), v);
-                }
-            }
-            (dst, src) => *dst = src,
-        }
-    }
-
     // Exact match template (handlebars)
+    // Front matter is stripped here before rendering, so .md.hbs files are never stripped twice
     match try_load_file(&try_path, request) {
         Ok(response) => match String::from_utf8(response.body().to_vec()) {
             Ok(body) => {
-                let matter = Matter::<YAML>::new();
-                let result = matter.parse(&body);
+                let content = extract_front_matter(&body, request);
+
+                // Opt-in weak ETag from the template source, which ignores any data it renders
+                let source_etag = match request.template_context().meta.get("etag_from_source") {
+                    Some(serde_json::Value::Bool(true)) => response
+                        .etag()
+                        .map(|etag| format!("W/{}", etag.trim_start_matches("W/"))),
+                    _ => None,
+                };
 
-                if let Some(front_matter) = result.data {
-                    let front_matter_json: serde_json::Value = front_matter.into();
-                    json_value_merge(&mut request.mut_template_context().meta, front_matter_json);
+                if let Some(source_etag) = &source_etag {
+                    if request_etag_matches(request, source_etag) {
+                        return Ok(Response::new_not_modified(source_etag));
+                    }
                 }
 
                 match render_response_body_for_request(
@@ -58,11 +80,22 @@ pub fn try_load_file_for_path(path: &str, request: &mut Request) -> Result<Respo
                     &Response::new(
                         *response.status(),
                         response.media_type(),
-                        result.content.as_bytes(),
+                        content.as_bytes(),
                         response.cacheable(),
                     ),
                 ) {
-                    Ok(rendered_response) => Ok(rendered_response),
+                    Ok(rendered_response) => {
+                        // Markdown is wrapped in its layout once it has been rendered as well
+                        let rendered_response = match try_path.ends_with(".md.hbs") {
+                            true => rendered_response,
+                            false => render_layout_for_request(path, request, rendered_response)?,
+                        };
+
+                        Ok(match source_etag {
+                            Some(source_etag) => rendered_response.with_etag(&source_etag),
+                            None => rendered_response,
+                        })
+                    }
                     Err(status) => Err(status),
                 }
             }
@@ -70,7 +103,7 @@ pub fn try_load_file_for_path(path: &str, request: &mut Request) -> Result<Respo
                 error!(
                     "[{}] [{}] [{}] [{}] Unicode error reading {} (valid up to {})",
                     request.protocol(),
-                    request.peer_addr(),
+                    request.client_ip(),
                     request.client_certificate_details(),
                     request.path(),
                     path,
@@ -83,23 +116,81 @@ pub fn try_load_file_for_path(path: &str, request: &mut Request) -> Result<Respo
     }
 }
 
+// Recursively merges objects, with src values replacing dst values otherwise
+pub fn json_value_merge(dst: &mut serde_json::Value, src: serde_json::Value) {
+    match (dst, src) {
+        (dst @ &mut serde_json::Value::Object(_), serde_json::Value::Object(src)) => {
+            let dst = dst.as_object_mut().unwrap();
+            for (k, v) in src {
+                json_value_merge(dst.entry(k).or_insert(serde_json::Value::Null), v);
+            }
+        }
+        (dst, src) => *dst = src,
+    }
+}
+
+// Front matter between +++ delimiters (as in Hugo) is TOML, otherwise it's YAML between ---
+pub fn parse_front_matter(body: &str) -> ParsedEntity {
+    match body.starts_with("+++") {
+        true => {
+            let mut matter = Matter::<TOML>::new();
+            matter.delimiter = "+++".to_string();
+            matter.parse(body)
+        }
+        false => Matter::<YAML>::new().parse(body),
+    }
+}
+
+// Merges any front matter into the template context meta and returns the remaining content
+fn extract_front_matter(body: &str, request: &mut Request) -> String {
+    let result = parse_front_matter(body);
+
+    if let Some(front_matter) = result.data {
+        let front_matter_json: serde_json::Value = front_matter.into();
+        json_value_merge(&mut request.mut_template_context().meta, front_matter_json);
+    }
+
+    result.content
+}
+
+// Checks every file and directory name, so i.e. anything under .git/ is blocked too. Dotfiles like
+// .env count as having their whole name (without the dot) as the extension
+pub fn has_blocked_file_extension(path: &Path, blocked_file_extensions: &[String]) -> bool {
+    path.components().any(|component| {
+        match component
+            .as_os_str()
+            .to_str()
+            .and_then(|name| name.rsplit_once('.'))
+        {
+            Some((_, extension)) => blocked_file_extensions
+                .iter()
+                .any(|blocked| blocked.eq_ignore_ascii_case(extension)),
+            None => false,
+        }
+    })
+}
+
+// If any file or directory name starts with a dot (i.e. .htaccess or .git/config)
+pub fn is_hidden_path(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
 fn try_load_file(path: &str, request: &mut Request) -> Result<Response, Status> {
     let path_buf = match PathBuf::from(&path).canonicalize() {
         Ok(path) => path,
         Err(_) => return Err(Status::NotFound),
     };
 
-    if !path_buf.starts_with(format!(
-        "{}/",
-        request.server_context().config().public_root_path()
-    )) && !path_buf.starts_with(format!(
-        "{}/",
-        request.server_context().config().errdocs_path()
-    )) {
+    if !path_buf.starts_with(format!("{}/", request.public_root_path()))
+        && !path_buf.starts_with(format!("{}/", request.errdocs_path()))
+    {
         error!(
             "[{}] [{}] [{}] [{}] {}: canonicalized path not in public root/errdocs dir - path traversal attempt? (canonicalized path: {})",
             request.protocol(),
-            request.peer_addr(),
+            request.client_ip(),
             request.client_certificate_details(),
             request.path(),
             Status::OtherClientError,
@@ -108,7 +199,70 @@ fn try_load_file(path: &str, request: &mut Request) -> Result<Response, Status>
         return Err(Status::OtherClientError);
     }
 
+    // Not found rather than unauthorized so blocked files don't reveal that they exist
+    let config = request.server_context().config();
+    let relative_path_buf = path_buf
+        .strip_prefix(request.public_root_path())
+        .or(path_buf.strip_prefix(request.errdocs_path()))
+        .unwrap_or(path_buf.as_path());
+    if has_blocked_file_extension(relative_path_buf, config.blocked_file_extensions())
+        || has_blocked_file_extension(
+            Path::new(Path::new(path).file_name().unwrap_or_default()),
+            config.blocked_file_extensions(),
+        )
+    {
+        info!(
+            "[{}] [{}] [{}] [{}] {}: file extension is in BLOCKED_FILE_EXTENSIONS: {}",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            Status::NotFound,
+            path
+        );
+        return Err(Status::NotFound);
+    }
+
+    if config.hidden_files_blocked() && is_hidden_path(relative_path_buf) {
+        info!(
+            "[{}] [{}] [{}] [{}] {}: hidden files are blocked by HIDDEN_FILES_BLOCKED: {}",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            Status::NotFound,
+            path
+        );
+        return Err(Status::NotFound);
+    }
+
     if path_buf.is_file() {
+        let is_rendered = path.ends_with(".hbs") || path.ends_with(".md");
+
+        if let Ok(metadata) = fs::metadata(&path_buf) {
+            // Checked first so the limit applies to streamed files too
+            let max_response_body_bytes = config.max_response_body_bytes();
+            if metadata.len() > max_response_body_bytes {
+                error!(
+                    "[{}] [{}] [{}] [{}] {}: file is bigger than MAX_RESPONSE_BODY_BYTES ({} > {} bytes): {}",
+                    request.protocol(),
+                    request.client_ip(),
+                    request.client_certificate_details(),
+                    request.path(),
+                    Status::OtherServerError,
+                    metadata.len(),
+                    max_response_body_bytes,
+                    path
+                );
+                return Err(Status::OtherServerError);
+            }
+
+            // Large static files are streamed when written instead of being read into memory
+            if !is_rendered && metadata.len() > config.streaming_threshold_bytes() {
+                return Ok(try_load_streamed_file(path, path_buf, metadata, request));
+            }
+        }
+
         let resp_file = request.server_context().fs_read(path_buf);
 
         return match resp_file {
@@ -129,14 +283,37 @@ fn try_load_file(path: &str, request: &mut Request) -> Result<Response, Status>
                     }
                 }
 
-                Ok(Response::new(
+                let modified: Option<DateTime<Utc>> = file
+                    .metadata()
+                    .modified()
+                    .ok()
+                    .map(|modified| modified.into());
+
+                // Templates/markdown are rendered so the source file's ETag/mtime don't apply
+                if !path.ends_with(".hbs")
+                    && !path.ends_with(".md")
+                    && request_is_not_modified(request, file.etag(), modified)
+                {
+                    return Ok(Response::new_not_modified(file.etag()));
+                }
+
+                let response = Response::new(
                     Status::Success,
-                    mime_guess::from_path(&path)
-                        .first_raw()
-                        .unwrap_or(&request.protocol().media_type()),
+                    &media_type_for_path(&path, request),
                     &file.data(),
                     true,
-                ))
+                )
+                .with_etag(file.etag());
+
+                let response = match is_rendered {
+                    true => response,
+                    false => response.with_accept_ranges(),
+                };
+
+                Ok(match modified {
+                    Some(modified) => response.with_last_modified(modified),
+                    None => response,
+                })
             }
             Err(_) => Err(Status::Unauthorized),
         };
@@ -144,3 +321,342 @@ fn try_load_file(path: &str, request: &mut Request) -> Result<Response, Status>
 
     Err(Status::NotFound)
 }
+
+// CUSTOM_MIME_TYPES takes precedence over mime_guess, then the protocols' own extensions (i.e. .gmi)
+// are recognised. Files without an extension are assumed to be the request protocol's markup
+pub fn media_type_for_path(path: &str, request: &Request) -> String {
+    let extension = match Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some(extension) => extension.to_ascii_lowercase(),
+        None => return request.protocol().media_type(),
+    };
+
+    if let Some(mime_type) = request
+        .server_context()
+        .config()
+        .custom_mime_types()
+        .get(&extension)
+    {
+        return mime_type.to_string();
+    }
+
+    if let Some(mime_type) = mime_guess::from_ext(&extension).first_raw() {
+        return mime_type.to_string();
+    }
+
+    match [Protocol::Gemini, Protocol::Https]
+        .into_iter()
+        .find(|protocol| protocol.media_type_file_extensions().contains(&extension))
+    {
+        Some(protocol) => protocol.media_type(),
+        None => "application/octet-stream".to_string(),
+    }
+}
+
+// Streamed files aren't hashed as they're never read into memory, so the weak ETag is based on size and mtime
+fn try_load_streamed_file(
+    path: &str,
+    path_buf: PathBuf,
+    metadata: fs::Metadata,
+    request: &Request,
+) -> Response {
+    let modified: Option<DateTime<Utc>> = metadata.modified().ok().map(|modified| modified.into());
+    let etag = format!(
+        "W/\"{:x}-{:x}\"",
+        metadata.len(),
+        modified.map(|modified| modified.timestamp()).unwrap_or(0)
+    );
+
+    if request_is_not_modified(request, &etag, modified) {
+        return Response::new_not_modified(&etag);
+    }
+
+    let response = Response::new_streamed_file(
+        &media_type_for_path(path, request),
+        path_buf,
+        metadata.len(),
+    )
+    .with_etag(&etag)
+    .with_accept_ranges();
+
+    match modified {
+        Some(modified) => response.with_last_modified(modified),
+        None => response,
+    }
+}
+
+// If-None-Match takes precedence over If-Modified-Since when both are present
+fn request_is_not_modified(request: &Request, etag: &str, modified: Option<DateTime<Utc>>) -> bool {
+    if request.header("If-None-Match").is_some() {
+        return request_etag_matches(request, etag);
+    }
+
+    let if_modified_since = request
+        .header("If-Modified-Since")
+        .and_then(|date_str| DateTime::parse_from_rfc2822(date_str).ok());
+
+    match (if_modified_since, modified) {
+        // HTTP dates only have second precision
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+// If-None-Match can be a list of (possibly weak) ETags or *, and uses weak comparison
+fn request_etag_matches(request: &Request, etag: &str) -> bool {
+    match request.header("If-None-Match") {
+        Some(if_none_match) => if_none_match.split(',').any(|request_etag| {
+            let request_etag = request_etag.trim();
+            request_etag == "*"
+                || request_etag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        }),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::context::ServerContext;
+    use crate::tls::ClientCertificateDetails;
+    use std::sync::Arc;
+    use url::Url;
+
+    // A site in its own temporary folder, removed when dropped
+    struct TestSite {
+        root_path: PathBuf,
+        server_context: Arc<ServerContext>,
+    }
+
+    impl TestSite {
+        fn new(name: &str) -> TestSite {
+            let root_path = std::env::temp_dir().join(format!(
+                "rubyshd-files-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root_path);
+
+            for folder in ["public_root", "partials", "data", "errdocs"] {
+                fs::create_dir_all(root_path.join(folder)).unwrap();
+            }
+            for filename in ["ca.pem", "cert.pem", "key.pem"] {
+                fs::write(root_path.join(filename), "").unwrap();
+            }
+
+            let config_path = root_path.join("config.toml");
+            fs::write(
+                &config_path,
+                format!(
+                    r#"public_root_path = "{root}/public_root"
+partials_path = "{root}/partials"
+data_path = "{root}/data"
+errdocs_path = "{root}/errdocs"
+tls_client_ca_certificate_pem_filename = "{root}/ca.pem"
+tls_server_certificate_pem_filename = "{root}/cert.pem"
+tls_server_private_key_pem_filename = "{root}/key.pem"
+redirects_data_file = "{root}/data/redirects.json"
+"#,
+                    root = root_path.display()
+                ),
+            )
+            .unwrap();
+
+            TestSite {
+                server_context: ServerContext::new_with_config(Config::new_from_file(&config_path)),
+                root_path: root_path,
+            }
+        }
+
+        // Writes a file relative to the site folder and returns its path
+        fn write(&self, path: &str, contents: &str) -> String {
+            let path_buf = self.root_path.join(path);
+            fs::create_dir_all(path_buf.parent().unwrap()).unwrap();
+            fs::write(&path_buf, contents).unwrap();
+
+            path_buf
+                .canonicalize()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+
+        fn request(&self, path: &str) -> Request {
+            Request::new(
+                self.server_context.clone(),
+                "127.0.0.1:4443".parse().unwrap(),
+                "127.0.0.1".parse().unwrap(),
+                Url::parse(&format!("https://localhost{}", path)).unwrap(),
+                ClientCertificateDetails::new_anonymous(),
+                "GET".to_string(),
+                Vec::new(),
+            )
+        }
+    }
+
+    impl Drop for TestSite {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root_path);
+        }
+    }
+
+    fn is_blocked(path: &str) -> bool {
+        has_blocked_file_extension(
+            Path::new(path),
+            &["pem".to_string(), "env".to_string(), "git".to_string()],
+        )
+    }
+
+    #[test]
+    fn blocked_file_extensions_are_case_insensitive() {
+        assert!(is_blocked("server.pem"));
+        assert!(is_blocked("server.PEM"));
+        assert!(is_blocked("keys/Server.Pem"));
+        assert!(!is_blocked("server.pem.txt"));
+        assert!(!is_blocked("pem"));
+    }
+
+    #[test]
+    fn blocked_file_extensions_include_dotfile_names() {
+        assert!(is_blocked(".env"));
+        assert!(is_blocked("config/.ENV"));
+        assert!(!is_blocked(".envrc"));
+    }
+
+    #[test]
+    fn blocked_file_extensions_apply_to_directories() {
+        assert!(is_blocked(".git/config"));
+        assert!(is_blocked("repo.git/HEAD"));
+        assert!(!is_blocked("git/config"));
+    }
+
+    #[test]
+    fn hidden_paths_have_a_dot_in_any_component() {
+        assert!(is_hidden_path(Path::new(".htaccess")));
+        assert!(is_hidden_path(Path::new(".git/config")));
+        assert!(is_hidden_path(Path::new("assets/.cache/style.css")));
+        assert!(!is_hidden_path(Path::new("assets/style.css")));
+        assert!(!is_hidden_path(Path::new("./assets/style.css")));
+    }
+
+    #[test]
+    fn blocked_files_are_not_served_but_can_still_be_read() {
+        let site = TestSite::new("blocked");
+        let path = site.write("public_root/secret.pem", "not a real key");
+
+        let mut request = site.request("/secret.pem");
+        assert!(matches!(
+            try_load_file_for_path(&path, &mut request),
+            Err(Status::NotFound)
+        ));
+
+        // i.e. for templates reading it through helpers
+        let file = site.server_context.fs_read(PathBuf::from(&path)).unwrap();
+        assert_eq!(file.data(), b"not a real key");
+    }
+
+    #[test]
+    fn toml_front_matter_is_between_plus_delimiters() {
+        let parsed =
+            parse_front_matter("+++\ntitle = \"Hello\"\ntags = [\"a\", \"b\"]\n+++\nBody\n");
+        let front_matter: serde_json::Value = parsed.data.unwrap().into();
+
+        assert_eq!(front_matter, json!({"title": "Hello", "tags": ["a", "b"]}));
+        assert_eq!(parsed.content.trim(), "Body");
+    }
+
+    #[test]
+    fn front_matter_falls_back_to_yaml() {
+        let parsed = parse_front_matter("---\ntitle: Hello\ntags: [a, b]\n---\nBody\n");
+        let front_matter: serde_json::Value = parsed.data.unwrap().into();
+
+        assert_eq!(front_matter, json!({"title": "Hello", "tags": ["a", "b"]}));
+        assert_eq!(parsed.content.trim(), "Body");
+
+        // TOML needs the +++ delimiters, so this is treated as (invalid) YAML
+        let parsed = parse_front_matter("---\ntitle = \"Hello\"\n---\nBody\n");
+        assert_ne!(
+            parsed.data.map(|data| -> serde_json::Value { data.into() }),
+            Some(json!({"title": "Hello"}))
+        );
+    }
+
+    #[test]
+    fn toml_front_matter_datetimes_reach_meta_as_strings() {
+        let site = TestSite::new("toml-datetimes");
+        let front_matter = "+++\ntitle = \"Hello\"\ncreated_at = 2024-01-02T03:04:05Z\n+++\n";
+
+        let path = site.write(
+            "public_root/post.md",
+            &format!("{}# Markdown body\n", front_matter),
+        );
+        let mut request = site.request("/post.md");
+        let response = try_load_file_for_path(&path, &mut request).ok().unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(response.body()).trim(),
+            "# Markdown body"
+        );
+        assert_eq!(request.template_context().meta["title"], "Hello");
+        assert_eq!(
+            request.template_context().meta["created_at"],
+            "2024-01-02T03:04:05Z"
+        );
+
+        let path = site.write(
+            "public_root/page.hbs",
+            &format!("{}<h1>{{{{meta.title}}}}</h1>\n", front_matter),
+        );
+        let mut request = site.request("/page");
+        let response = try_load_file_for_path(&path, &mut request).ok().unwrap();
+
+        assert!(String::from_utf8_lossy(response.body()).contains("<h1>Hello</h1>"));
+        assert_eq!(
+            request.template_context().meta["created_at"],
+            "2024-01-02T03:04:05Z"
+        );
+    }
+
+    #[test]
+    fn md_hbs_front_matter_is_only_stripped_once() {
+        let site = TestSite::new("md-hbs");
+        // The second block is part of the page (i.e. a thematic break), not more front matter
+        let path = site.write(
+            "public_root/post.md.hbs",
+            "---\ntitle: Hello\n---\n---\nnot: front matter\n---\n# {{meta.title}}\n",
+        );
+
+        let mut request = site.request("/post");
+        let response = try_load_file_for_path(&path, &mut request).ok().unwrap();
+        let body = String::from_utf8_lossy(response.body()).to_string();
+
+        assert!(!body.contains("title: Hello"), "{:?}", body);
+        assert!(body.contains("not: front matter"), "{:?}", body);
+        assert!(body.contains("# Hello"), "{:?}", body);
+        assert_eq!(request.template_context().meta["title"], "Hello");
+        assert!(request.template_context().meta.get("not").is_none());
+    }
+
+    #[test]
+    fn md_front_matter_lands_in_meta() {
+        let site = TestSite::new("md");
+        let path = site.write(
+            "public_root/post.md",
+            "---\ntitle: Hello\ntags: [a, b]\n---\n# Markdown body\n",
+        );
+
+        let mut request = site.request("/post.md");
+        let response = try_load_file_for_path(&path, &mut request).ok().unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(response.body()).trim(),
+            "# Markdown body"
+        );
+        assert_eq!(request.template_context().meta["title"], "Hello");
+        assert_eq!(request.template_context().meta["tags"], json!(["a", "b"]));
+    }
+}
@@ -1,22 +1,43 @@
+mod cidr;
 mod config;
+mod connlimit;
 mod context;
+mod feed;
 mod files;
+mod metrics;
+mod plain_redirect;
 mod protocol;
+mod ratelimit;
+mod redirects;
 mod request;
 mod response;
 mod router;
 mod templates;
 mod tls;
 
+use crate::cidr::cidr_list_contains;
+use crate::connlimit::ConnectionGuard;
 use crate::protocol::Protocol;
+use crate::response::{Response, Status};
+use crate::tls::ClientCertificateDetails;
 use config::Config;
 use context::ServerContext;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use plain_redirect::handle_plain_redirect_connection;
 use router::route_request;
 use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, path::Path};
 use tokio::io::{copy, sink, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::rustls;
+use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
 
 #[cfg(target_os = "openbsd")]
@@ -31,15 +52,49 @@ pub fn setup_pledge_and_unveil(server_config: &Config) {
 
     unveil("/dev/urandom", "r").expect("could not unveil urandom");
     unveil(server_config.public_root_path(), "rx").expect("could not unveil public docs folder");
-    unveil(server_config.partials_path(), "rx").expect("could not unveil template partials folder");
+    for virtual_host_path in server_config.virtual_hosts().values() {
+        unveil(virtual_host_path, "rx").expect("could not unveil virtual host public docs folder");
+    }
+    for partials_path in server_config.partials_paths() {
+        unveil(partials_path, "rx").expect("could not unveil template partials folder");
+    }
     unveil(server_config.errdocs_path(), "rx").expect("could not unveil error docs folder");
+    for protocol_public_root_path in [
+        server_config.gemini_public_root_path(),
+        server_config.https_public_root_path(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        unveil(protocol_public_root_path, "rx")
+            .expect("could not unveil protocol public docs folder");
+    }
+    for protocol_errdocs_path in [
+        server_config.gemini_errdocs_path(),
+        server_config.https_errdocs_path(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        unveil(protocol_errdocs_path, "rx").expect("could not unveil protocol error docs folder");
+    }
     unveil(server_config.data_path(), "rx").expect("could not unveil data folder");
-    unveil(server_config.tls_client_ca_certificate_pem_filename(), "r")
-        .expect("could not unveil TLS CA certificate");
+    if let Some(well_known_path) = server_config.well_known_path() {
+        unveil(well_known_path, "r").expect("could not unveil well-known folder");
+    }
+    for ca_certificate_pem_filename in server_config.tls_client_ca_certificate_pem_filenames() {
+        unveil(ca_certificate_pem_filename, "r").expect("could not unveil TLS CA certificate");
+    }
     unveil(server_config.tls_server_certificate_pem_filename(), "r")
         .expect("could not unveil TLS server certificate");
     unveil(server_config.tls_server_private_key_pem_filename(), "r")
         .expect("could not unveil TLS server private key");
+    for (certificate_pem_filename, private_key_pem_filename) in
+        server_config.tls_sni_certs().values()
+    {
+        unveil(certificate_pem_filename, "r").expect("could not unveil TLS SNI certificate");
+        unveil(private_key_pem_filename, "r").expect("could not unveil TLS SNI private key");
+    }
 
     unveil::disable();
 }
@@ -49,11 +104,224 @@ pub fn setup_pledge_and_unveil(_: &Config) {
     debug!("not openbsd. :(");
 }
 
+#[cfg(target_os = "linux")]
+pub fn setup_landlock(server_config: &Config) {
+    use landlock::{
+        Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+
+    debug!("linux, restricting filesystem access with landlock");
+
+    let abi = ABI::V1;
+    let read = AccessFs::from_read(abi);
+    let read_execute = read | AccessFs::Execute;
+
+    let mut paths: Vec<(&str, _)> = vec![
+        ("/dev/urandom", read),
+        (server_config.public_root_path(), read_execute),
+        (server_config.errdocs_path(), read),
+        (server_config.data_path(), read),
+        (server_config.tls_server_certificate_pem_filename(), read),
+        (server_config.tls_server_private_key_pem_filename(), read),
+    ];
+    if let Some(well_known_path) = server_config.well_known_path() {
+        paths.push((well_known_path, read));
+    }
+    for virtual_host_path in server_config.virtual_hosts().values() {
+        paths.push((virtual_host_path.as_str(), read_execute));
+    }
+    for partials_path in server_config.partials_paths() {
+        paths.push((partials_path.as_str(), read));
+    }
+    for protocol_public_root_path in [
+        server_config.gemini_public_root_path(),
+        server_config.https_public_root_path(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        paths.push((protocol_public_root_path, read_execute));
+    }
+    for protocol_errdocs_path in [
+        server_config.gemini_errdocs_path(),
+        server_config.https_errdocs_path(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        paths.push((protocol_errdocs_path, read));
+    }
+    for ca_certificate_pem_filename in server_config.tls_client_ca_certificate_pem_filenames() {
+        paths.push((ca_certificate_pem_filename.as_str(), read));
+    }
+    for (certificate_pem_filename, private_key_pem_filename) in
+        server_config.tls_sni_certs().values()
+    {
+        paths.push((certificate_pem_filename.as_str(), read));
+        paths.push((private_key_pem_filename.as_str(), read));
+    }
+
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .expect("could not handle landlock filesystem access")
+        .create()
+        .expect("could not create landlock ruleset");
+    for (path, access) in paths {
+        let path_fd = PathFd::new(path).expect("could not open path for landlock rule");
+        ruleset = ruleset
+            .add_rule(PathBeneath::new(path_fd, access))
+            .expect("could not add landlock rule");
+    }
+
+    // Kernels without landlock (before 5.13, or with it disabled) still run, just unrestricted
+    match ruleset
+        .restrict_self()
+        .expect("could not restrict filesystem access with landlock")
+        .ruleset
+    {
+        RulesetStatus::FullyEnforced => debug!("landlock fully enforced"),
+        RulesetStatus::PartiallyEnforced => {
+            warn!("landlock only partially enforced by this kernel")
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("landlock is not supported by this kernel, filesystem access is not restricted")
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn setup_landlock(_: &Config) {
+    debug!("not linux, no landlock");
+}
+
+// Syscalls needed after startup by the runtime, TLS, file serving and the listeners. Anything else
+// fails with EPERM rather than killing the process, so a missing syscall shows up as an I/O error
+#[cfg(target_os = "linux")]
+const SECCOMP_ALLOWED_SYSCALLS: &[i64] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_ppoll,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_futex,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_prctl,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+];
+
+#[cfg(target_os = "linux")]
+pub fn setup_seccomp_filter() {
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+    use std::collections::BTreeMap;
+
+    debug!("linux, installing seccomp filter");
+
+    // An empty rule list allows the syscall with any arguments
+    let rules = SECCOMP_ALLOWED_SYSCALLS
+        .iter()
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        env::consts::ARCH
+            .try_into()
+            .expect("unsupported architecture for seccomp filter"),
+    )
+    .expect("could not build seccomp filter");
+
+    let program: BpfProgram = filter.try_into().expect("could not compile seccomp filter");
+
+    // The runtime's worker threads already exist, so the filter is synced to all of them
+    seccompiler::apply_filter_all_threads(&program).expect("could not apply seccomp filter");
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn setup_seccomp_filter() {
+    debug!("not linux, no seccomp filter");
+}
+
+// Each connection holds one permit while in flight, so acquiring all of them waits for every connection
+const MAX_IN_FLIGHT_CONNECTIONS: u32 = 1 << 20;
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     env_logger::init();
 
-    let server_context = Arc::new(ServerContext::new_with_config(Config::new_from_env()));
+    let config = match env::var("RUBYSHD_CONFIG") {
+        Ok(config_path) => Config::new_from_file(Path::new(&config_path)),
+        Err(_) => Config::new_from_env(),
+    };
+
+    let server_context = ServerContext::new_with_config(config);
 
     info!(
         "Starting server with config: {:#?}",
@@ -63,69 +331,416 @@ async fn main() -> io::Result<()> {
     debug!("Page Metatadata: {:#?}", server_context.get_page_metadata());
 
     setup_pledge_and_unveil(server_context.config());
+    setup_landlock(server_context.config());
 
     let tls_config = tls::make_config(&server_context.config());
 
-    let acceptor = TlsAcceptor::from(tls_config);
+    setup_seccomp_filter();
+
+    // Listeners build their acceptor from the latest config, which SIGHUP replaces
+    let (tls_config_sender, tls_config_receiver) = watch::channel(tls_config);
 
-    let listener = TcpListener::bind(server_context.config().tls_listen_bind()).await?;
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_CONNECTIONS as usize));
 
+    // One task per listener, all sharing the same context and TLS config
+    let mut listeners = JoinSet::new();
+
+    for bind in server_context.config().tls_listen_binds() {
+        let listener = TcpListener::bind(bind).await?;
+        info!("Listening for TLS connections on {}", bind);
+        listeners.spawn(accept_tls_connections(
+            listener,
+            tls_config_receiver.clone(),
+            server_context.clone(),
+            shutting_down.clone(),
+            in_flight.clone(),
+        ));
+    }
+
+    if let Some(bind) = server_context.config().plain_redirect_bind() {
+        let listener = TcpListener::bind(bind).await?;
+        info!("Listening for plain HTTP redirect connections on {}", bind);
+        listeners.spawn(accept_plain_redirect_connections(
+            listener,
+            server_context.clone(),
+            shutting_down.clone(),
+            in_flight.clone(),
+        ));
+    }
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    // Listeners only return if accepting a connection fails
     loop {
-        let (stream, peer_addr) = listener.accept().await?;
-        let acceptor = acceptor.clone();
-        let server_context = server_context.clone();
+        tokio::select! {
+            result = listeners.join_next() => match result {
+                Some(result) => result.map_err(|err| io::Error::new(io::ErrorKind::Other, err))??,
+                None => return Ok(()),
+            },
+            _ = sigterm.recv() => break,
+            _ = sighup.recv() => reload_tls_config(&server_context, &tls_config_sender).await,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
 
-        let fut = async move {
-            let mut stream = acceptor.accept(stream).await?;
+    // Stop accepting new connections and give in-flight ones a chance to finish
+    shutting_down.store(true, Ordering::SeqCst);
+    listeners.abort_all();
 
-            let client_certificate_details =
-                tls::extract_client_certificate_details_from_stream(&stream);
+    let in_flight_count = || MAX_IN_FLIGHT_CONNECTIONS as usize - in_flight.available_permits();
+    let shutdown_timeout_seconds = server_context.config().shutdown_timeout_seconds();
 
-            let mut buf = vec![0u8; server_context.config().max_request_header_size()];
-            if stream.read(&mut buf[..]).await? == server_context.config().max_request_header_size()
-            {
-                error!("Request from {}: request bigger than max size", peer_addr);
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "request bigger than max size",
-                ));
+    info!(
+        "Shutting down, waiting up to {}s for {} in-flight connections",
+        shutdown_timeout_seconds,
+        in_flight_count()
+    );
+
+    match tokio::time::timeout(
+        Duration::from_secs(shutdown_timeout_seconds),
+        in_flight.acquire_many(MAX_IN_FLIGHT_CONNECTIONS),
+    )
+    .await
+    {
+        Ok(_) => info!("Shutdown complete with no in-flight connections"),
+        Err(_) => error!(
+            "Shutdown timed out with {} in-flight connections",
+            in_flight_count()
+        ),
+    }
+
+    Ok(())
+}
+
+// make_config panics on invalid certificates, so it runs on its own task and the old config is kept if it does
+async fn reload_tls_config(
+    server_context: &Arc<ServerContext>,
+    tls_config_sender: &watch::Sender<Arc<rustls::ServerConfig>>,
+) {
+    info!("Reloading TLS certificates");
+
+    let server_context = server_context.clone();
+    match tokio::task::spawn_blocking(move || tls::make_config(server_context.config())).await {
+        Ok(tls_config) => {
+            tls_config_sender.send_replace(tls_config);
+            info!("Reloaded TLS certificates");
+        }
+        Err(err) => error!(
+            "Could not reload TLS certificates, keeping the old ones: {}",
+            err
+        ),
+    }
+}
+
+// Checks IP_DENYLIST then (if set) IP_ALLOWLIST, blocked connections are closed without a response
+fn is_peer_blocked(server_context: &ServerContext, peer_addr: SocketAddr) -> bool {
+    let config = server_context.config();
+
+    if let Some(cidr) = config
+        .ip_denylist()
+        .iter()
+        .find(|cidr| cidr.contains(peer_addr.ip()))
+    {
+        info!(
+            "Connection from {} blocked: in IP_DENYLIST ({})",
+            peer_addr, cidr
+        );
+        return true;
+    }
+
+    if !config.ip_allowlist().is_empty()
+        && !cidr_list_contains(config.ip_allowlist(), peer_addr.ip())
+    {
+        info!("Connection from {} blocked: not in IP_ALLOWLIST", peer_addr);
+        return true;
+    }
+
+    false
+}
+
+// Connections over MAX_CONNECTIONS_PER_IP or MAX_TOTAL_CONNECTIONS are closed without a response
+fn try_acquire_connection(
+    server_context: &ServerContext,
+    peer_addr: SocketAddr,
+) -> Option<ConnectionGuard> {
+    let connection_guard = server_context
+        .connection_limiter()
+        .try_acquire(peer_addr.ip());
+
+    if connection_guard.is_none() {
+        info!("Connection from {} closed: too many connections", peer_addr);
+        server_context.metrics().record_rejected_connection();
+    }
+    connection_guard
+}
+
+async fn accept_tls_connections(
+    listener: TcpListener,
+    tls_config: watch::Receiver<Arc<rustls::ServerConfig>>,
+    server_context: Arc<ServerContext>,
+    shutting_down: Arc<AtomicBool>,
+    in_flight: Arc<Semaphore>,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if shutting_down.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        if is_peer_blocked(&server_context, peer_addr) {
+            drop(stream);
+            continue;
+        }
+
+        let connection_guard = match try_acquire_connection(&server_context, peer_addr) {
+            Some(connection_guard) => connection_guard,
+            None => {
+                drop(stream);
+                continue;
             }
+        };
 
-            let request = Protocol::parse_req_buf(
-                server_context,
+        let permit = match in_flight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return Ok(()),
+        };
+        let acceptor = TlsAcceptor::from(tls_config.borrow().clone());
+        let server_context = server_context.clone();
+
+        let shutting_down = shutting_down.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_tls_connection(
+                acceptor,
+                server_context.clone(),
+                stream,
                 peer_addr,
-                &client_certificate_details,
-                &buf,
-                &mut stream,
+                shutting_down,
             )
-            .await;
-
-            match request {
-                Ok(mut request) => {
-                    let response = route_request(&mut request);
-
-                    request
-                        .protocol()
-                        .write_response(response, &mut stream)
-                        .await?;
-                }
-                Err(err) => {
-                    error!("ERROR [{} ->] msg = {}", peer_addr, err);
-                }
+            .await
+            {
+                error!("Connection from {}: {:?}", peer_addr, err);
             }
+            drop(connection_guard);
+            drop(permit);
+        });
+    }
+}
 
-            stream.shutdown().await?;
+async fn accept_plain_redirect_connections(
+    listener: TcpListener,
+    server_context: Arc<ServerContext>,
+    shutting_down: Arc<AtomicBool>,
+    in_flight: Arc<Semaphore>,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if shutting_down.load(Ordering::SeqCst) {
+            return Ok(());
+        }
 
-            let mut output = sink();
-            copy(&mut stream, &mut output).await?;
+        if is_peer_blocked(&server_context, peer_addr) {
+            drop(stream);
+            continue;
+        }
+
+        let connection_guard = match try_acquire_connection(&server_context, peer_addr) {
+            Some(connection_guard) => connection_guard,
+            None => {
+                drop(stream);
+                continue;
+            }
+        };
 
-            Ok(()) as io::Result<()>
+        let permit = match in_flight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return Ok(()),
         };
+        let server_context = server_context.clone();
 
         tokio::spawn(async move {
-            if let Err(err) = fut.await {
-                eprintln!("{:?}", err);
+            if let Err(err) =
+                handle_plain_redirect_connection(server_context.clone(), stream, peer_addr).await
+            {
+                error!("Connection from {}: {:?}", peer_addr, err);
             }
+            drop(connection_guard);
+            drop(permit);
         });
     }
 }
+
+async fn handle_tls_connection(
+    acceptor: TlsAcceptor,
+    server_context: Arc<ServerContext>,
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    shutting_down: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let _active_connection = server_context.metrics().track_connection();
+
+    let handshake_timeout =
+        Duration::from_millis(server_context.config().tls_handshake_timeout_ms());
+    let mut stream = match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+        Ok(stream) => stream?,
+        Err(_) => {
+            error!("Request from {}: TLS handshake timed out", peer_addr);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            ));
+        }
+    };
+
+    let client_certificate_details = tls::extract_client_certificate_details_from_stream(&stream);
+
+    let max_keepalive_requests = server_context.config().max_keepalive_requests().max(1);
+    let mut buf = vec![0u8; server_context.config().max_request_header_size()];
+
+    // Handle requests until one doesn't ask for the connection to be kept alive
+    for request_index in 0..max_keepalive_requests {
+        buf.fill(0);
+
+        let is_last_request =
+            request_index + 1 == max_keepalive_requests || shutting_down.load(Ordering::SeqCst);
+
+        let keep_alive = handle_tls_request(
+            server_context.clone(),
+            &mut stream,
+            peer_addr,
+            &client_certificate_details,
+            &mut buf,
+            request_index == 0,
+            is_last_request,
+        )
+        .await?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    stream.shutdown().await?;
+
+    let mut output = sink();
+    copy(&mut stream, &mut output).await?;
+
+    Ok(())
+}
+
+// Reads and responds to a single request, returning whether the connection can be reused for another
+async fn handle_tls_request(
+    server_context: Arc<ServerContext>,
+    stream: &mut TlsStream<TcpStream>,
+    peer_addr: SocketAddr,
+    client_certificate_details: &ClientCertificateDetails,
+    buf: &mut [u8],
+    is_first_request: bool,
+    is_last_request: bool,
+) -> io::Result<bool> {
+    let request_timeout = Duration::from_millis(server_context.config().request_timeout_ms());
+    let bytes_read = match tokio::time::timeout(request_timeout, stream.read(buf)).await {
+        Ok(bytes_read) => bytes_read?,
+        // An idle kept-alive connection is closed quietly
+        Err(_) if !is_first_request => return Ok(false),
+        Err(_) => {
+            error!("Request from {}: timed out waiting for request", peer_addr);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for request",
+            ));
+        }
+    };
+
+    if bytes_read == 0 && !is_first_request {
+        return Ok(false);
+    }
+
+    if bytes_read == server_context.config().max_request_header_size() {
+        error!("Request from {}: request bigger than max size", peer_addr);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "request bigger than max size",
+        ));
+    }
+
+    // Requests via a trusted proxy are rate limited once the forwarded client IP is known
+    let is_from_trusted_proxy = cidr_list_contains(
+        server_context.config().trusted_proxy_cidrs(),
+        peer_addr.ip(),
+    );
+
+    if !is_from_trusted_proxy && !server_context.rate_limiter().check(peer_addr.ip()) {
+        error!("Request from {}: rate limited", peer_addr);
+        Protocol::from_req_buf(buf)
+            .write_early_error_response(
+                server_context,
+                peer_addr,
+                client_certificate_details,
+                Status::RateLimit,
+                stream,
+            )
+            .await;
+        return Ok(false);
+    }
+
+    let request = Protocol::parse_req_buf(
+        server_context,
+        peer_addr,
+        client_certificate_details,
+        buf,
+        stream,
+    )
+    .await;
+
+    let mut request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            error!("ERROR [{} ->] msg = {}", peer_addr, err);
+            return Ok(false);
+        }
+    };
+
+    if is_last_request {
+        request = request.with_keep_alive(false);
+    }
+
+    if is_from_trusted_proxy
+        && !request
+            .server_context()
+            .rate_limiter()
+            .check(request.client_ip())
+    {
+        error!(
+            "Request from {} (via {}): rate limited",
+            request.client_ip(),
+            peer_addr
+        );
+        let mut request = request.with_keep_alive(false);
+        let response = Response::new_for_request_and_status(&mut request, Status::RateLimit);
+        request
+            .protocol()
+            .write_response(&request, response, stream)
+            .await?;
+        return Ok(false);
+    }
+
+    let started_at = Instant::now();
+    let response = route_request(&mut request);
+    let status = *response.status();
+
+    request
+        .protocol()
+        .write_response(&request, response, stream)
+        .await?;
+
+    request.server_context().metrics().record_request(
+        request.protocol(),
+        &status,
+        started_at.elapsed(),
+    );
+
+    Ok(request.keep_alive())
+}
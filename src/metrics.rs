@@ -0,0 +1,191 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::protocol::Protocol;
+use crate::response::Status;
+
+pub const METRICS_MEDIA_TYPE: &str = "text/plain; version=0.0.4";
+
+const PROTOCOLS: [Protocol; 2] = [Protocol::Https, Protocol::Gemini];
+
+const STATUS_CLASSES: [&str; 4] = ["2xx", "3xx", "4xx", "5xx"];
+
+// Upper bounds (in seconds) of the request latency histogram buckets, excluding +Inf
+const LATENCY_BUCKETS_SECONDS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct LatencyHistogram {
+    // Not cumulative - the last bucket is +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS_SECONDS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: [[AtomicU64; STATUS_CLASSES.len()]; PROTOCOLS.len()],
+    latency: [LatencyHistogram; PROTOCOLS.len()],
+    active_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+}
+
+// Decrements the active connection gauge when dropped
+pub struct ActiveConnectionGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ActiveConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn protocol_index(protocol: Protocol) -> usize {
+    match protocol {
+        Protocol::Https => 0,
+        Protocol::Gemini => 1,
+    }
+}
+
+// Classes match HTTPS status codes regardless of protocol
+fn status_class_index(status: &Status) -> usize {
+    match status {
+        Status::Success
+        | Status::NoContent
+        | Status::GeminiInput
+        | Status::GeminiSensitiveInput => 0,
+        Status::NotModified | Status::TemporaryRedirect | Status::PermanentRedirect => 1,
+        Status::Unauthenticated
+        | Status::Unauthorized
+        | Status::NotFound
+        | Status::Gone
+        | Status::MethodNotAllowed
+        | Status::RequestTooLarge
+        | Status::RangeNotSatisfiable
+        | Status::RateLimit
+        | Status::OtherClientError => 2,
+        Status::OtherServerError => 3,
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn track_connection(&self) -> ActiveConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnectionGuard { metrics: self }
+    }
+
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_request(&self, protocol: Protocol, status: &Status, duration: Duration) {
+        let protocol_index = protocol_index(protocol);
+
+        self.requests[protocol_index][status_class_index(status)].fetch_add(1, Ordering::Relaxed);
+
+        let latency = &self.latency[protocol_index];
+        let seconds = duration.as_secs_f64();
+        let bucket_index = LATENCY_BUCKETS_SECONDS
+            .iter()
+            .position(|upper_bound| seconds <= *upper_bound)
+            .unwrap_or(LATENCY_BUCKETS_SECONDS.len());
+
+        latency.buckets[bucket_index].fetch_add(1, Ordering::Relaxed);
+        latency
+            .sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        latency.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text exposition format (https://prometheus.io/docs/instrumenting/exposition_formats/)
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP rubyshd_requests_total Requests handled by protocol and status class."
+        );
+        let _ = writeln!(out, "# TYPE rubyshd_requests_total counter");
+        for protocol in PROTOCOLS {
+            for (class_index, class) in STATUS_CLASSES.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "rubyshd_requests_total{{protocol=\"{}\",status_class=\"{}\"}} {}",
+                    protocol,
+                    class,
+                    self.requests[protocol_index(protocol)][class_index].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP rubyshd_request_duration_seconds Time taken to route and respond to requests."
+        );
+        let _ = writeln!(out, "# TYPE rubyshd_request_duration_seconds histogram");
+        for protocol in PROTOCOLS {
+            let latency = &self.latency[protocol_index(protocol)];
+            let mut cumulative_count = 0;
+
+            for (bucket_index, bucket) in latency.buckets.iter().enumerate() {
+                cumulative_count += bucket.load(Ordering::Relaxed);
+                let upper_bound = match LATENCY_BUCKETS_SECONDS.get(bucket_index) {
+                    Some(upper_bound) => upper_bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+
+                let _ = writeln!(
+                    out,
+                    "rubyshd_request_duration_seconds_bucket{{protocol=\"{}\",le=\"{}\"}} {}",
+                    protocol, upper_bound, cumulative_count
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "rubyshd_request_duration_seconds_sum{{protocol=\"{}\"}} {}",
+                protocol,
+                latency.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "rubyshd_request_duration_seconds_count{{protocol=\"{}\"}} {}",
+                protocol,
+                latency.count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP rubyshd_active_connections Connections currently being handled."
+        );
+        let _ = writeln!(out, "# TYPE rubyshd_active_connections gauge");
+        let _ = writeln!(
+            out,
+            "rubyshd_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP rubyshd_rejected_connections_total Connections closed for exceeding MAX_CONNECTIONS_PER_IP or MAX_TOTAL_CONNECTIONS."
+        );
+        let _ = writeln!(out, "# TYPE rubyshd_rejected_connections_total counter");
+        let _ = writeln!(
+            out,
+            "rubyshd_rejected_connections_total {}",
+            self.rejected_connections.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
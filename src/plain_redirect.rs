@@ -0,0 +1,95 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::context::ServerContext;
+
+// Answers a plain-text HTTP request with a permanent redirect to the HTTPS equivalent, without TLS
+pub async fn handle_plain_redirect_connection(
+    server_context: Arc<ServerContext>,
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; server_context.config().max_request_header_size()];
+    let request_timeout = Duration::from_millis(server_context.config().request_timeout_ms());
+    let bytes_read = match tokio::time::timeout(request_timeout, stream.read(&mut buf[..])).await {
+        Ok(bytes_read) => bytes_read?,
+        Err(_) => {
+            error!("Request from {}: timed out waiting for request", peer_addr);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out waiting for request",
+            ));
+        }
+    };
+
+    if bytes_read == server_context.config().max_request_header_size() {
+        error!("Request from {}: request bigger than max size", peer_addr);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "request bigger than max size",
+        ));
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut r = httparse::Request::new(&mut headers);
+
+    let (path, host) = match r.parse(&buf) {
+        Ok(_) => (
+            r.path.unwrap_or("/").to_string(),
+            r.headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("Host"))
+                .and_then(|header| std::str::from_utf8(header.value).ok())
+                .map(|host| host.to_string()),
+        ),
+        Err(err) => {
+            error!(
+                "[HTTP] [{}] [] [] error parsing plain request: {}",
+                peer_addr, err
+            );
+            stream.shutdown().await?;
+            return Ok(());
+        }
+    };
+
+    // Drop any port from the Host header as the HTTPS equivalent is on the default port
+    let hostname = match host {
+        Some(host) => match host.rsplit_once(':') {
+            Some((hostname, port)) if !hostname.is_empty() && port.parse::<u16>().is_ok() => {
+                hostname.to_string()
+            }
+            _ => host,
+        },
+        None => server_context.config().default_hostname().to_string(),
+    };
+
+    let path = match path.starts_with('/') {
+        true => path,
+        false => format!("/{}", path),
+    };
+
+    let location = format!("https://{}{}", hostname, path);
+
+    info!(
+        "[HTTP] [{}] [] [{}] 301 (redirect to {})",
+        peer_addr, path, location
+    );
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                location
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
@@ -1,19 +1,26 @@
+use crate::cidr::cidr_list_contains;
+use crate::config::Config;
 use crate::context::ServerContext;
-use crate::request::Request;
-use crate::response::{Response, Status};
+use crate::request::{ByteRange, Request};
+use crate::response::{Response, ResponseBody, Status};
 use crate::tls::ClientCertificateDetails;
+use chrono::DateTime;
+use flate2::write::GzEncoder;
+use log::error;
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 use std::fmt;
-use std::io::Error;
-use std::net::SocketAddr;
+use std::io::{Error, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 use url::Url;
 
-const CACHEABLE_MAX_AGE_SECONDS: u16 = 14_400;
+const CACHEABLE_MAX_AGE_SECONDS: u32 = 14_400;
 
 struct HttpHeaderEntry {
     name: String,
@@ -24,6 +31,84 @@ fn newline_stripped_safe_str(str: &str) -> &str {
     str.lines().next().unwrap_or("")
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContentEncoding::Brotli => write!(f, "br"),
+            ContentEncoding::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+impl ContentEncoding {
+    // Picks the best encoding the client accepts, if the response is worth compressing at all
+    fn negotiate(request: &Request, response: &Response) -> Option<ContentEncoding> {
+        let config = request.server_context().config();
+
+        if !config.enable_response_compression()
+            || matches!(response.body_source(), ResponseBody::File { .. })
+            || response.body().len() < config.min_compression_bytes()
+            || !(response.media_type().starts_with("text/")
+                || response.media_type().starts_with("application/json"))
+        {
+            return None;
+        }
+
+        let accepted_encodings = request
+            .header("Accept-Encoding")?
+            .split(',')
+            .filter_map(|encoding| {
+                let mut parts = encoding.split(';');
+                let name = parts.next()?.trim().to_ascii_lowercase();
+                let is_rejected = parts.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        == Some(0.0)
+                });
+
+                match is_rejected {
+                    true => None,
+                    false => Some(name),
+                }
+            })
+            .collect::<Vec<String>>();
+
+        if accepted_encodings.iter().any(|encoding| encoding == "br") {
+            Some(ContentEncoding::Brotli)
+        } else if accepted_encodings.iter().any(|encoding| encoding == "gzip") {
+            Some(ContentEncoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            ContentEncoding::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                    writer.write_all(body)?;
+                }
+                Ok(compressed)
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
 pub enum Protocol {
     Gemini,
@@ -75,21 +160,75 @@ impl Protocol {
         }
     }
 
+    pub fn from_req_buf(buf: &[u8]) -> Protocol {
+        match buf.starts_with(b"gemini:") {
+            true => Protocol::Gemini,
+            false => Protocol::Https,
+        }
+    }
+
+    // Best effort response for requests that were rejected before being parsed into a Request
+    pub async fn write_early_error_response(
+        &self,
+        server_context: Arc<ServerContext>,
+        peer_addr: SocketAddr,
+        client_certificate_details: &ClientCertificateDetails,
+        status: Status,
+        stream: &mut TlsStream<TcpStream>,
+    ) {
+        let url = match self {
+            Protocol::Gemini => Url::parse("gemini://localhost/").unwrap(),
+            Protocol::Https => Url::parse("https://localhost/").unwrap(),
+        };
+
+        let mut request = Request::new(
+            server_context,
+            peer_addr,
+            peer_addr.ip(),
+            url,
+            client_certificate_details.clone(),
+            "".to_string(),
+            Vec::new(),
+        );
+        let response = Response::new_for_request_and_status(&mut request, status);
+
+        let _ = self.write_response(&request, response, stream).await;
+    }
+
     pub async fn write_response(
         &self,
+        request: &Request,
         response: Response,
         stream: &mut TlsStream<TcpStream>,
     ) -> Result<(), Error> {
         match self {
             Protocol::Gemini => {
+                // Gemtext can declare its language with a lang parameter on the media type
+                let media_type = match page_language(request) {
+                    Some(language) if response.media_type().starts_with("text/gemini") => {
+                        format!("{}; lang={}", response.media_type(), language)
+                    }
+                    _ => response.media_type().to_string(),
+                };
+
                 let (status, prompt_content_type_uri_or_error) = match response.status() {
-                    Status::Success => (20, response.media_type()),
+                    Status::Success => (20, media_type.as_str()),
+                    Status::NoContent => (20, media_type.as_str()), // never sent as only HTTPS preflight requests get it
+                    Status::NotModified => (40, "Not Modified"), // never sent as Gemini has no conditional requests
                     Status::TemporaryRedirect => (30, response.redirect_uri()),
                     Status::PermanentRedirect => (31, response.redirect_uri()),
-                    Status::Unauthenticated => (60, "Unauthorized"),
+                    Status::Unauthenticated => match response.input_prompt() {
+                        "" => (60, "Unauthorized"),
+                        auth_prompt => (60, auth_prompt), // from the require-auth helper
+                    },
                     Status::Unauthorized => (61, "Forbidden"),
                     Status::NotFound => (51, "Not Found"),
+                    Status::Gone => (52, "Gone"),
+                    Status::MethodNotAllowed => (59, "Method Not Allowed"),
                     Status::RequestTooLarge => (59, "Payload Too Large"),
+                    Status::RangeNotSatisfiable => (59, "Range Not Satisfiable"), // never sent as Gemini has no ranges
+                    Status::GeminiInput => (10, response.input_prompt()),
+                    Status::GeminiSensitiveInput => (11, response.input_prompt()),
                     Status::RateLimit => (44, "Too Many Requests"),
                     Status::OtherServerError => (40, "Internal Server Error"),
                     Status::OtherClientError => (59, "Bad Request"),
@@ -106,32 +245,133 @@ impl Protocol {
 
                 // only write body if it's a 20
                 if status == 20 {
-                    stream.write_all(response.body()).await?;
+                    match response.body_source() {
+                        ResponseBody::File { path, len } => {
+                            write_file_body(path, 0, *len, stream).await?
+                        }
+                        ResponseBody::Bytes(body) => stream.write_all(body).await?,
+                    }
                 }
             }
             Protocol::Https => {
-                let (status, reason) = match response.status() {
+                let total_len = match response.body_source() {
+                    ResponseBody::File { len, .. } => *len,
+                    ResponseBody::Bytes(body) => body.len() as u64,
+                };
+
+                // None if no range applies, Some(None) if the requested range can't be satisfied
+                let byte_range =
+                    match (response.status(), response.accept_ranges(), request.range()) {
+                        (Status::Success, true, Some(range))
+                            if if_range_matches(request, &response) =>
+                        {
+                            Some(range.resolve(total_len))
+                        }
+                        _ => None,
+                    };
+
+                let response_status = match byte_range {
+                    Some(None) => Status::RangeNotSatisfiable,
+                    _ => *response.status(),
+                };
+
+                let (status, reason) = match response_status {
                     Status::Success => (200, "OK"),
+                    Status::NoContent => (204, "No Content"),
+                    Status::NotModified => (304, "Not Modified"),
                     Status::PermanentRedirect => (301, "Moved Permanently"),
                     Status::TemporaryRedirect => (302, "Found"),
                     Status::OtherClientError => (400, "Bad Request"),
                     Status::Unauthenticated => (401, "Unauthenticated"), // this is intentionally not "Unauthorized"
                     Status::Unauthorized => (403, "Forbidden"),
                     Status::NotFound => (404, "Not Found"),
+                    Status::Gone => (410, "Gone"),
+                    Status::MethodNotAllowed => (405, "Method Not Allowed"),
                     Status::RequestTooLarge => (413, "Payload Too Large"),
+                    Status::RangeNotSatisfiable => (416, "Range Not Satisfiable"),
+                    Status::GeminiInput | Status::GeminiSensitiveInput => (400, "Bad Request"), // never sent as input prompts are Gemini only
                     Status::RateLimit => (429, "Too Many Requests"),
                     Status::OtherServerError => (500, "Internal Server Error"),
                 };
 
-                let body_len = response.body().len();
+                let (status, reason) = match byte_range {
+                    Some(Some(_)) => (206, "Partial Content"),
+                    _ => (status, reason),
+                };
+
+                // Ranges are of the uncompressed body
+                let (body, content_encoding) = match ContentEncoding::negotiate(request, &response)
+                    .filter(|_| byte_range.is_none())
+                {
+                    Some(encoding) => match encoding.compress(response.body()) {
+                        Ok(compressed_body) => (compressed_body, Some(encoding)),
+                        Err(err) => {
+                            error!(
+                                "[{}] [{}] [{}] [{}] Error compressing response with {}: {}",
+                                request.protocol(),
+                                request.client_ip(),
+                                request.client_certificate_details(),
+                                request.path(),
+                                encoding,
+                                err
+                            );
+                            (response.body().to_vec(), None)
+                        }
+                    },
+                    None => (response.body().to_vec(), None),
+                };
+
+                let (body_offset, body_len) = match (byte_range, response.body_source()) {
+                    (Some(Some((start, end))), _) => (start, end - start + 1),
+                    (Some(None), _) => (0, 0),
+                    (None, ResponseBody::File { len, .. }) => (0, *len),
+                    (None, ResponseBody::Bytes(_)) => (0, body.len() as u64),
+                };
 
                 let mut headers: Vec<HttpHeaderEntry> = Vec::new();
 
                 // Default headers
-                headers.push(HttpHeaderEntry {
-                    name: "Content-Length".to_string(),
-                    value: body_len.to_string(),
-                });
+                if status != 204 && status != 304 {
+                    headers.push(HttpHeaderEntry {
+                        name: "Content-Length".to_string(),
+                        value: body_len.to_string(),
+                    });
+                }
+
+                match byte_range {
+                    Some(Some((start, end))) => headers.push(HttpHeaderEntry {
+                        name: "Content-Range".to_string(),
+                        value: format!("bytes {}-{}/{}", start, end, total_len),
+                    }),
+                    Some(None) => headers.push(HttpHeaderEntry {
+                        name: "Content-Range".to_string(),
+                        value: format!("bytes */{}", total_len),
+                    }),
+                    None => {}
+                }
+
+                if let (200 | 206, true) = (status, response.accept_ranges()) {
+                    headers.push(HttpHeaderEntry {
+                        name: "Accept-Ranges".to_string(),
+                        value: "bytes".to_string(),
+                    });
+                }
+
+                if let (200 | 206 | 304, Some(etag)) = (status, response.etag()) {
+                    headers.push(HttpHeaderEntry {
+                        name: "ETag".to_string(),
+                        value: etag.to_string(),
+                    });
+                }
+
+                if let (200 | 206 | 304, Some(last_modified)) = (status, response.last_modified()) {
+                    headers.push(HttpHeaderEntry {
+                        name: "Last-Modified".to_string(),
+                        value: last_modified
+                            .format("%a, %d %b %Y %H:%M:%S GMT")
+                            .to_string(),
+                    });
+                }
 
                 if body_len > 0 {
                     headers.push(HttpHeaderEntry {
@@ -139,22 +379,57 @@ impl Protocol {
                         value: response.media_type().to_string(),
                     });
 
-                    let cache_max_age = match response.cacheable() {
-                        true => CACHEABLE_MAX_AGE_SECONDS,
-                        false => 0,
-                    };
+                    if let Some(language) = page_language(request) {
+                        headers.push(HttpHeaderEntry {
+                            name: "Content-Language".to_string(),
+                            value: language.to_string(),
+                        });
+                    }
 
                     headers.push(HttpHeaderEntry {
                         name: "Cache-Control".to_string(),
-                        value: format!("public, max-age={}, must-revalidate", cache_max_age),
+                        value: cache_control(request, &response),
+                    });
+
+                    if request
+                        .server_context()
+                        .config()
+                        .enable_response_compression()
+                    {
+                        headers.push(HttpHeaderEntry {
+                            name: "Vary".to_string(),
+                            value: "Accept-Encoding".to_string(),
+                        });
+                    }
+                }
+
+                if let Some(encoding) = content_encoding {
+                    headers.push(HttpHeaderEntry {
+                        name: "Content-Encoding".to_string(),
+                        value: encoding.to_string(),
                     });
                 }
 
+                headers.push(HttpHeaderEntry {
+                    name: "Connection".to_string(),
+                    value: match request.keep_alive() {
+                        true => "keep-alive".to_string(),
+                        false => "close".to_string(),
+                    },
+                });
+
                 headers.push(HttpHeaderEntry {
                     name: "Server".to_string(),
                     value: "rubyshd".to_string(),
                 });
 
+                if status == 405 {
+                    headers.push(HttpHeaderEntry {
+                        name: "Allow".to_string(),
+                        value: "GET, HEAD, OPTIONS".to_string(),
+                    });
+                }
+
                 if status == 301 || status == 302 {
                     headers.push(HttpHeaderEntry {
                         name: "Location".to_string(),
@@ -162,11 +437,121 @@ impl Protocol {
                     });
                 }
 
+                let config = request.server_context().config();
+
+                // HSTS is only sent on non-error responses
+                if config.hsts_max_age_seconds() > 0 && status < 400 {
+                    headers.push(HttpHeaderEntry {
+                        name: "Strict-Transport-Security".to_string(),
+                        value: match config.hsts_include_subdomains() {
+                            true => format!(
+                                "max-age={}; includeSubDomains",
+                                config.hsts_max_age_seconds()
+                            ),
+                            false => format!("max-age={}", config.hsts_max_age_seconds()),
+                        },
+                    });
+                }
+
+                // Pages can override the global policy with csp_override in their front matter
+                let content_security_policy = match request
+                    .template_context()
+                    .meta
+                    .get("csp_override")
+                    .and_then(|csp_override| csp_override.as_str())
+                {
+                    Some(csp_override) => csp_override,
+                    None => config.content_security_policy(),
+                };
+
+                if !content_security_policy.is_empty() {
+                    headers.push(HttpHeaderEntry {
+                        name: "Content-Security-Policy".to_string(),
+                        value: content_security_policy.to_string(),
+                    });
+                }
+
+                if request
+                    .template_context()
+                    .meta
+                    .get("noindex")
+                    .and_then(|noindex| noindex.as_bool())
+                    .unwrap_or(false)
+                {
+                    headers.push(HttpHeaderEntry {
+                        name: "X-Robots-Tag".to_string(),
+                        value: "noindex".to_string(),
+                    });
+                }
+
                 headers.push(HttpHeaderEntry {
-                    name: "Access-Control-Allow-Origin".to_string(),
-                    value: "*".to_string(),
+                    name: "X-Content-Type-Options".to_string(),
+                    value: "nosniff".to_string(),
                 });
 
+                if !config.x_frame_options().is_empty() {
+                    headers.push(HttpHeaderEntry {
+                        name: "X-Frame-Options".to_string(),
+                        value: config.x_frame_options().to_string(),
+                    });
+                }
+
+                if !config.permissions_policy().is_empty() {
+                    headers.push(HttpHeaderEntry {
+                        name: "Permissions-Policy".to_string(),
+                        value: config.permissions_policy().to_string(),
+                    });
+                }
+
+                if !config.referrer_policy().is_empty() {
+                    headers.push(HttpHeaderEntry {
+                        name: "Referrer-Policy".to_string(),
+                        value: config.referrer_policy().to_string(),
+                    });
+                }
+
+                if let Some(allow_origin) = cors_allow_origin(request) {
+                    // Pages can override the allowed methods with cors_methods in their front matter
+                    let allow_methods = request
+                        .template_context()
+                        .meta
+                        .get("cors_methods")
+                        .and_then(|cors_methods| cors_methods.as_str())
+                        .unwrap_or(config.cors_allow_methods());
+
+                    if allow_origin != "*" {
+                        headers.push(HttpHeaderEntry {
+                            name: "Vary".to_string(),
+                            value: "Origin".to_string(),
+                        });
+
+                        // Browsers reject credentials for a wildcard origin
+                        if config.cors_allow_credentials() {
+                            headers.push(HttpHeaderEntry {
+                                name: "Access-Control-Allow-Credentials".to_string(),
+                                value: "true".to_string(),
+                            });
+                        }
+                    }
+
+                    headers.push(HttpHeaderEntry {
+                        name: "Access-Control-Allow-Origin".to_string(),
+                        value: allow_origin,
+                    });
+
+                    headers.push(HttpHeaderEntry {
+                        name: "Access-Control-Allow-Methods".to_string(),
+                        value: allow_methods.to_string(),
+                    });
+                }
+
+                for (name, value) in config.extra_response_headers() {
+                    headers.push(HttpHeaderEntry {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+
                 // Headers
                 stream.write_all(&b"HTTP/1.1 "[..]).await?;
                 stream.write_all(status.to_string().as_bytes()).await?;
@@ -189,10 +574,21 @@ impl Protocol {
 
                 stream.write_all(&b"\r\n"[..]).await?;
 
-                // Body
-                stream.write_all(response.body()).await?;
-
-                stream.write_all(&b"\r\n"[..]).await?;
+                // Body (nothing can follow it as the connection may be reused)
+                // HEAD responses get the same headers as GET, including the real Content-Length
+                if status != 204 && status != 304 && request.method() != "HEAD" {
+                    match response.body_source() {
+                        ResponseBody::File { path, .. } => {
+                            write_file_body(path, body_offset, body_len, stream).await?
+                        }
+                        ResponseBody::Bytes(_) => {
+                            let body_offset = body_offset as usize;
+                            stream
+                                .write_all(&body[body_offset..body_offset + body_len as usize])
+                                .await?
+                        }
+                    }
+                }
             }
         }
 
@@ -212,17 +608,12 @@ impl Protocol {
                 let raw_url = match std::str::from_utf8(buf) {
                     Ok(buf_str) => buf_str.lines().next().unwrap(),
                     Err(e) => {
-                        let _ = Protocol::Gemini
-                            .write_response(
-                                Response::new_for_request_and_status(
-                                    &mut Request::new(
-                                        server_context,
-                                        peer_addr,
-                                        Url::parse("gemini://localhost/").unwrap(),
-                                        client_certificate_details.clone(),
-                                    ),
-                                    Status::OtherClientError,
-                                ),
+                        Protocol::Gemini
+                            .write_early_error_response(
+                                server_context,
+                                peer_addr,
+                                client_certificate_details,
+                                Status::OtherClientError,
                                 stream,
                             )
                             .await;
@@ -236,17 +627,12 @@ impl Protocol {
                 let url = match Url::parse(raw_url) {
                     Ok(url) => url,
                     Err(e) => {
-                        let _ = Protocol::Gemini
-                            .write_response(
-                                Response::new_for_request_and_status(
-                                    &mut Request::new(
-                                        server_context,
-                                        peer_addr,
-                                        Url::parse("gemini://localhost/").unwrap(),
-                                        client_certificate_details.clone(),
-                                    ),
-                                    Status::OtherClientError,
-                                ),
+                        Protocol::Gemini
+                            .write_early_error_response(
+                                server_context,
+                                peer_addr,
+                                client_certificate_details,
+                                Status::OtherClientError,
                                 stream,
                             )
                             .await;
@@ -257,8 +643,11 @@ impl Protocol {
                 Ok(Request::new(
                     server_context,
                     peer_addr,
+                    peer_addr.ip(),
                     url,
                     client_certificate_details.clone(),
+                    "".to_string(),
+                    Vec::new(),
                 ))
             }
             _ => {
@@ -268,17 +657,12 @@ impl Protocol {
                 let status = match httparse::ParserConfig::default().parse_request(&mut r, &buf) {
                     Ok(status) => status,
                     Err(e) => {
-                        let _ = Protocol::Https
-                            .write_response(
-                                Response::new_for_request_and_status(
-                                    &mut Request::new(
-                                        server_context,
-                                        peer_addr,
-                                        Url::parse("https://localhost/").unwrap(),
-                                        client_certificate_details.clone(),
-                                    ),
-                                    Status::OtherClientError,
-                                ),
+                        Protocol::Https
+                            .write_early_error_response(
+                                server_context,
+                                peer_addr,
+                                client_certificate_details,
+                                Status::OtherClientError,
                                 stream,
                             )
                             .await;
@@ -289,17 +673,12 @@ impl Protocol {
                 match status {
                     httparse::Status::Complete(_) => (),
                     httparse::Status::Partial => {
-                        let _ = Protocol::Https
-                            .write_response(
-                                Response::new_for_request_and_status(
-                                    &mut Request::new(
-                                        server_context,
-                                        peer_addr,
-                                        Url::parse("https://localhost/").unwrap(),
-                                        client_certificate_details.clone(),
-                                    ),
-                                    Status::RequestTooLarge,
-                                ),
+                        Protocol::Https
+                            .write_early_error_response(
+                                server_context,
+                                peer_addr,
+                                client_certificate_details,
+                                Status::RequestTooLarge,
                                 stream,
                             )
                             .await;
@@ -307,6 +686,8 @@ impl Protocol {
                     }
                 };
 
+                let method = r.method.unwrap_or("GET").to_string();
+                let version = r.version;
                 let path = r.path.unwrap_or("/").to_string();
 
                 let hostname = match headers
@@ -323,17 +704,12 @@ impl Protocol {
                 let url = match Url::parse(format!("https://{}{}", hostname, path).as_str()) {
                     Ok(url) => url,
                     Err(e) => {
-                        let _ = Protocol::Https
-                            .write_response(
-                                Response::new_for_request_and_status(
-                                    &mut Request::new(
-                                        server_context,
-                                        peer_addr,
-                                        Url::parse("https://localhost/").unwrap(),
-                                        client_certificate_details.clone(),
-                                    ),
-                                    Status::OtherClientError,
-                                ),
+                        Protocol::Https
+                            .write_early_error_response(
+                                server_context,
+                                peer_addr,
+                                client_certificate_details,
+                                Status::OtherClientError,
                                 stream,
                             )
                             .await;
@@ -341,13 +717,189 @@ impl Protocol {
                     }
                 };
 
+                let headers = headers
+                    .iter()
+                    .filter(|h| !h.name.is_empty())
+                    .filter_map(|h| match std::str::from_utf8(h.value) {
+                        Ok(value) => Some((h.name.to_string(), value.to_string())),
+                        Err(_) => None,
+                    })
+                    .collect::<Vec<(String, String)>>();
+
+                let client_ip =
+                    forwarded_client_ip(server_context.config(), peer_addr.ip(), &headers);
+
+                // HTTP/1.1 defaults to persistent connections, HTTP/1.0 has to ask for them. Anything
+                // that might have a body is closed afterwards as the body isn't read
+                let connection = headers
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+                    .map(|(_, value)| value.to_ascii_lowercase());
+                let keep_alive = ["GET", "HEAD"].contains(&method.as_str())
+                    && match connection.as_deref() {
+                        Some("close") => false,
+                        Some("keep-alive") => true,
+                        _ => version == Some(1),
+                    };
+
+                // HEAD responses never send a body so ranges only apply to GET
+                let range = headers
+                    .iter()
+                    .filter(|_| method == "GET")
+                    .find(|(name, _)| name.eq_ignore_ascii_case("Range"))
+                    .and_then(|(_, value)| ByteRange::parse(value));
+
                 Ok(Request::new(
                     server_context,
                     peer_addr,
+                    client_ip,
                     url,
                     client_certificate_details.clone(),
-                ))
+                    method,
+                    headers,
+                )
+                .with_keep_alive(keep_alive)
+                .with_range(range))
             }
         }
     }
 }
+
+// Copies at most len bytes so the body always matches the Content-Length sent, even if the file has grown
+async fn write_file_body(
+    path: &Path,
+    offset: u64,
+    len: u64,
+    stream: &mut TlsStream<TcpStream>,
+) -> Result<(), Error> {
+    let mut file = File::open(path).await?;
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset)).await?;
+    }
+    tokio::io::copy(&mut file.take(len), stream).await?;
+
+    Ok(())
+}
+
+// The lang (or language) in the page's front matter, if any
+fn page_language(request: &Request) -> Option<&str> {
+    let meta = &request.template_context().meta;
+
+    meta.get("lang")
+        .or(meta.get("language"))
+        .and_then(|language| language.as_str())
+        .filter(|language| !language.is_empty())
+}
+
+// Front matter can make a page private (never cached) or override its max-age, with 0 meaning no-cache
+fn cache_control(request: &Request, response: &Response) -> String {
+    let meta = &request.template_context().meta;
+
+    if let Some(serde_json::Value::Bool(true)) = meta.get("cache_private") {
+        return "private, no-store".to_string();
+    }
+
+    let cache_max_age = match meta
+        .get("cache_max_age")
+        .and_then(|cache_max_age| cache_max_age.as_u64())
+    {
+        Some(0) => return "no-cache".to_string(),
+        Some(cache_max_age) => cache_max_age,
+        None => match response.cacheable() {
+            true => response
+                .cache_max_age_seconds()
+                .unwrap_or(CACHEABLE_MAX_AGE_SECONDS) as u64,
+            false => 0,
+        },
+    };
+
+    format!("public, max-age={}, must-revalidate", cache_max_age)
+}
+
+// If-Range only allows the range if the resource is unchanged, by strong ETag or Last-Modified date
+fn if_range_matches(request: &Request, response: &Response) -> bool {
+    let if_range = match request.header("If-Range") {
+        Some(if_range) => if_range.trim(),
+        None => return true,
+    };
+
+    if if_range.starts_with('"') {
+        return response.etag() == Some(if_range);
+    }
+
+    match (
+        DateTime::parse_from_rfc2822(if_range),
+        response.last_modified(),
+    ) {
+        (Ok(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+// The Access-Control-Allow-Origin value for the request's Origin, if it is allowed by CORS_ALLOW_ORIGINS
+// (or cors_origin in the page's front matter, which takes precedence)
+fn cors_allow_origin(request: &Request) -> Option<String> {
+    let allow_origins = match request
+        .template_context()
+        .meta
+        .get("cors_origin")
+        .and_then(|cors_origin| cors_origin.as_str())
+    {
+        Some(cors_origin) => cors_origin
+            .split(',')
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect::<Vec<String>>(),
+        None => request
+            .server_context()
+            .config()
+            .cors_allow_origins()
+            .to_vec(),
+    };
+
+    if allow_origins.iter().any(|origin| origin == "*") {
+        return Some("*".to_string());
+    }
+
+    let origin = request.header("Origin")?;
+    allow_origins
+        .iter()
+        .find(|allow_origin| allow_origin.eq_ignore_ascii_case(origin))
+        .map(|_| origin.to_string())
+}
+
+// Only trusts X-Forwarded-For/X-Real-IP from TRUSTED_PROXY_CIDRS, walking X-Forwarded-For from the right as
+// anything left of the last trusted proxy could have been set by the client
+fn forwarded_client_ip(config: &Config, peer_ip: IpAddr, headers: &[(String, String)]) -> IpAddr {
+    let trusted_proxy_cidrs = config.trusted_proxy_cidrs();
+    if !cidr_list_contains(trusted_proxy_cidrs, peer_ip) {
+        return peer_ip;
+    }
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+
+    if let Some(forwarded_for) = header("X-Forwarded-For") {
+        let forwarded_ips = forwarded_for
+            .split(',')
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<IpAddr>>();
+
+        return forwarded_ips
+            .iter()
+            .rev()
+            .find(|ip| !cidr_list_contains(trusted_proxy_cidrs, **ip))
+            .or(forwarded_ips.first())
+            .copied()
+            .unwrap_or(peer_ip);
+    }
+
+    match header("X-Real-IP").and_then(|ip| ip.trim().parse::<IpAddr>().ok()) {
+        Some(ip) => ip,
+        None => peer_ip,
+    }
+}
@@ -0,0 +1,83 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Stale (full) buckets are dropped once there are this many tracked IPs
+const MAX_RATE_LIMIT_BUCKETS: usize = 10_000;
+
+// At most one sweep per interval, as with many partially drained buckets a sweep may drop nothing
+const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub struct RateLimiter {
+    requests_per_second: u32,
+    burst: u32,
+    buckets: DashMap<IpAddr, TokenBucket>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            requests_per_second: requests_per_second,
+            burst: burst,
+            buckets: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Returns false if the IP has run out of tokens (a requests_per_second of 0 disables limiting)
+    pub fn check(&self, ip: IpAddr) -> bool {
+        if self.requests_per_second == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+
+        if self.buckets.len() > MAX_RATE_LIMIT_BUCKETS {
+            self.sweep(now);
+        }
+
+        let mut bucket = self.buckets.entry(ip).or_insert_with(|| TokenBucket {
+            tokens: self.burst as f64,
+            last_refill: now,
+        });
+
+        bucket.tokens = self.refilled_tokens(&bucket, now);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Skipped if another request is already sweeping
+    fn sweep(&self, now: Instant) {
+        let mut last_sweep = match self.last_sweep.try_lock() {
+            Ok(last_sweep) => last_sweep,
+            Err(_) => return,
+        };
+
+        if now.duration_since(*last_sweep) < RATE_LIMIT_SWEEP_INTERVAL {
+            return;
+        }
+        *last_sweep = now;
+
+        self.buckets
+            .retain(|_, bucket| self.refilled_tokens(bucket, now) < self.burst as f64);
+    }
+
+    fn refilled_tokens(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+        (bucket.tokens + elapsed * self.requests_per_second as f64).min(self.burst as f64)
+    }
+}
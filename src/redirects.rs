@@ -0,0 +1,70 @@
+use std::fs;
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::response::Status;
+
+// A `from` ending in this matches any path with the preceding prefix
+const WILDCARD_SUFFIX: &str = "*";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedirectRule {
+    from: String,
+    to: String,
+    #[serde(default)]
+    permanent: bool,
+}
+
+impl RedirectRule {
+    pub fn status(&self) -> Status {
+        match self.permanent {
+            true => Status::PermanentRedirect,
+            false => Status::TemporaryRedirect,
+        }
+    }
+
+    // Returns the redirect target if the rule matches, carrying over the wildcard remainder if `to` also ends with one
+    pub fn redirect_uri_for_path(&self, path: &str) -> Option<String> {
+        match self.from.strip_suffix(WILDCARD_SUFFIX) {
+            Some(from_prefix) => {
+                let remainder = path.strip_prefix(from_prefix)?;
+                match self.to.strip_suffix(WILDCARD_SUFFIX) {
+                    Some(to_prefix) => Some(format!("{}{}", to_prefix, remainder)),
+                    None => Some(self.to.clone()),
+                }
+            }
+            None => match self.from == path {
+                true => Some(self.to.clone()),
+                false => None,
+            },
+        }
+    }
+}
+
+// A missing file just means there are no redirects, but a malformed one is a configuration error
+pub fn load_redirect_rules(path: &str) -> Vec<RedirectRule> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            warn!("Not loading redirects from {}: {}", path, err);
+            return Vec::new();
+        }
+    };
+
+    let redirect_rules: Vec<RedirectRule> =
+        serde_json::from_str(&data).expect("Invalid REDIRECTS_DATA_FILE");
+
+    info!("Loaded {} redirects from {}", redirect_rules.len(), path);
+
+    redirect_rules
+}
+
+// Rules are checked in file order, so more specific rules should come before wildcards
+pub fn find_redirect(redirect_rules: &[RedirectRule], path: &str) -> Option<(Status, String)> {
+    redirect_rules.iter().find_map(|redirect_rule| {
+        redirect_rule
+            .redirect_uri_for_path(path)
+            .map(|redirect_uri| (redirect_rule.status(), redirect_uri))
+    })
+}
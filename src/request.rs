@@ -1,72 +1,201 @@
-use crate::context::ServerContext;
+use crate::context::{PageMetadata, ServerContext};
 use crate::protocol::Protocol;
 use crate::templates::{Markup, TemplateRequestContext};
 use crate::tls::ClientCertificateDetails;
+use percent_encoding::percent_decode_str;
 use serde_json::json;
 use std::env;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use url::Url;
 
 pub struct Request {
     server_context: Arc<ServerContext>,
-    peer_addr: SocketAddr,
+    client_ip: IpAddr,
     url: Url,
     client_certificate_details: ClientCertificateDetails,
+    method: String,
+    headers: Vec<(String, String)>,
     protocol: Protocol,
     template_context: TemplateRequestContext,
+    keep_alive: bool,
+    range: Option<ByteRange>,
+}
+
+// A single byte range from a Range header (i.e. `bytes=0-499`, `bytes=500-` or `bytes=-500`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    FromStart(u64, Option<u64>),
+    Suffix(u64),
+}
+
+impl ByteRange {
+    // Multiple ranges and other units aren't supported, so the header is ignored for them
+    pub fn parse(header_value: &str) -> Option<ByteRange> {
+        let range = header_value.trim().strip_prefix("bytes=")?.trim();
+        if range.contains(',') {
+            return None;
+        }
+
+        let (start, end) = range.split_once('-')?;
+        match (start.trim(), end.trim()) {
+            ("", suffix_len) => Some(ByteRange::Suffix(suffix_len.parse().ok()?)),
+            (start, "") => Some(ByteRange::FromStart(start.parse().ok()?, None)),
+            (start, end) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                match start <= end {
+                    true => Some(ByteRange::FromStart(start, Some(end))),
+                    false => None,
+                }
+            }
+        }
+    }
+
+    // The inclusive start and end offsets for a body of len bytes, or None if it can't be satisfied
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        if len == 0 {
+            return None;
+        }
+
+        match *self {
+            ByteRange::FromStart(start, _) if start >= len => None,
+            ByteRange::FromStart(start, end) => {
+                Some((start, end.map(|end| end.min(len - 1)).unwrap_or(len - 1)))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(suffix_len) => Some((len.saturating_sub(suffix_len), len - 1)),
+        }
+    }
 }
 
 impl Request {
     pub fn new(
         server_context: Arc<ServerContext>,
         peer_addr: SocketAddr,
+        client_ip: IpAddr,
         url: Url,
         client_certificate_details: ClientCertificateDetails,
+        method: String,
+        headers: Vec<(String, String)>,
     ) -> Request {
         let protocol = match url.scheme() {
             "gemini" => Protocol::Gemini,
             _ => Protocol::Https,
         };
 
+        let posts = server_context
+            .get_sorted_posts_for_protocol(protocol, server_context.config().posts_sort_order());
+        let (next_post, prev_post) = adjacent_posts(&posts, url.path());
+
         let template_context = TemplateRequestContext {
             meta: json!({}),
             data: server_context.get_data(),
-            posts: server_context.get_sorted_posts_for_protocol(protocol),
+            posts: posts,
+            tags: server_context.get_all_tags(),
+            next_post: next_post,
+            prev_post: prev_post,
             peer_addr: peer_addr,
+            client_ip: client_ip,
+            method: method.clone(),
+            hostname: url.host_str().unwrap_or("").to_string(),
             path: (url.path()).to_string(),
+            query: query_to_json(&url),
+            // The whole query string is the user's answer to a Gemini input prompt
+            gemini_query: match protocol {
+                Protocol::Gemini => url
+                    .query()
+                    .map(|query| percent_decode_str(query).decode_utf8_lossy().into_owned()),
+                Protocol::Https => None,
+            },
             is_authenticated: !client_certificate_details.is_anonymous(),
             is_anonymous: client_certificate_details.is_anonymous(),
             common_name: client_certificate_details.common_name(),
+            cert_serial: client_certificate_details
+                .serial()
+                .map(|serial| serial.to_string()),
+            cert_not_after: client_certificate_details.not_after().copied(),
+            cert_sans: client_certificate_details.sans().to_vec(),
+            cert_organization: client_certificate_details
+                .organization()
+                .map(|organization| organization.to_string()),
+            cert_fingerprint: client_certificate_details
+                .fingerprint()
+                .map(|fingerprint| fingerprint.to_string()),
             protocol: protocol,
             markup: Markup::default_for_protocol(protocol),
             is_gemini: protocol == Protocol::Gemini,
             is_https: protocol == Protocol::Https,
             os_platform: env::consts::OS.to_string(),
+            word_count: None,
         };
 
         Request {
             server_context: server_context,
-            peer_addr: peer_addr,
+            client_ip: client_ip,
             url: url,
             client_certificate_details: client_certificate_details,
+            method: method,
+            headers: headers,
             protocol: protocol,
             template_context: template_context,
+            keep_alive: false,
+            range: None,
         }
     }
 
+    pub fn with_range(mut self, range: Option<ByteRange>) -> Request {
+        self.range = range;
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Request {
+        self.keep_alive = keep_alive;
+        self
+    }
+
     pub fn server_context(&self) -> &Arc<ServerContext> {
         &self.server_context
     }
 
-    pub fn peer_addr(&self) -> &SocketAddr {
-        &self.peer_addr
+    // The client's IP, which is taken from forwarding headers if the peer is a trusted proxy
+    pub fn client_ip(&self) -> IpAddr {
+        self.client_ip
     }
 
     pub fn client_certificate_details(&self) -> &ClientCertificateDetails {
         &self.client_certificate_details
     }
 
+    // Only HTTPS requests have a method (empty for Gemini)
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    // Only HTTPS requests have headers
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn hostname(&self) -> &str {
+        self.url.host_str().unwrap_or("")
+    }
+
+    // The public root for the request's virtual host or protocol
+    pub fn public_root_path(&self) -> &str {
+        self.server_context
+            .config()
+            .public_root_path_for(self.protocol, self.hostname())
+    }
+
+    pub fn errdocs_path(&self) -> &str {
+        self.server_context
+            .config()
+            .errdocs_path_for_protocol(self.protocol)
+    }
+
     pub fn path(&self) -> &str {
         self.url.path()
     }
@@ -75,6 +204,16 @@ impl Request {
         self.protocol
     }
 
+    // Whether the connection should be kept open for another request after this one
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    // Only HTTPS requests with a valid single range Range header have one
+    pub fn range(&self) -> Option<ByteRange> {
+        self.range
+    }
+
     pub fn template_context(&self) -> &TemplateRequestContext {
         &self.template_context
     }
@@ -83,3 +222,84 @@ impl Request {
         &mut self.template_context
     }
 }
+
+// Returns the posts after and before the requested page in `posts` order, or nulls if it is not a post
+fn adjacent_posts(posts: &[PageMetadata], path: &str) -> (serde_json::Value, serde_json::Value) {
+    // Page metadata paths have no trailing slash or markup extension
+    let mut page_path = match path.strip_suffix('/') {
+        Some(stripped_path) if !stripped_path.is_empty() => stripped_path,
+        _ => path,
+    };
+    for extension in [".html", ".gmi", ".md"] {
+        page_path = page_path.strip_suffix(extension).unwrap_or(page_path);
+    }
+
+    let index = match posts.iter().position(|post| post.path() == page_path) {
+        Some(index) => index,
+        None => return (json!(null), json!(null)),
+    };
+
+    let post_json = |post: Option<&PageMetadata>| match post {
+        Some(post) => json!(post),
+        None => json!(null),
+    };
+
+    (
+        post_json(posts.get(index + 1)),
+        post_json(index.checked_sub(1).and_then(|index| posts.get(index))),
+    )
+}
+
+// Repeated keys (i.e. ?tag=a&tag=b) become arrays
+fn query_to_json(url: &Url) -> serde_json::Value {
+    let mut query = serde_json::Map::new();
+
+    for (key, value) in url.query_pairs() {
+        let value = serde_json::Value::String(value.into_owned());
+
+        match query.get_mut(&*key) {
+            Some(serde_json::Value::Array(values)) => values.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = json!([first, value]);
+            }
+            None => {
+                query.insert(key.into_owned(), value);
+            }
+        }
+    }
+
+    serde_json::Value::Object(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_json(url: &str) -> serde_json::Value {
+        query_to_json(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn query_to_json_collects_repeated_keys_into_arrays() {
+        assert_eq!(
+            query_json("https://localhost/search?tag=a&q=rust&tag=b&tag=c"),
+            json!({"tag": ["a", "b", "c"], "q": "rust"})
+        );
+    }
+
+    #[test]
+    fn query_to_json_decodes_percent_encoded_pairs() {
+        assert_eq!(
+            query_json("https://localhost/search?q=hello%20world&caf%C3%A9=%E2%9C%93&plus=a+b"),
+            json!({"q": "hello world", "café": "✓", "plus": "a b"})
+        );
+    }
+
+    #[test]
+    fn query_to_json_is_empty_without_a_query() {
+        assert_eq!(query_json("https://localhost/search"), json!({}));
+        assert_eq!(query_json("https://localhost/search?"), json!({}));
+        assert_eq!(query_json("gemini://localhost/search?q="), json!({"q": ""}));
+    }
+}
@@ -1,16 +1,24 @@
-use std::{fmt, str::FromStr};
+use chrono::{DateTime, Utc};
+use std::{fmt, path::PathBuf, str::FromStr};
 
 use crate::{files::try_load_file_for_path, request::Request};
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum Status {
     Success,
+    NoContent,
+    NotModified,
     TemporaryRedirect,
     PermanentRedirect,
     Unauthenticated,
     Unauthorized,
     NotFound,
+    Gone,
+    MethodNotAllowed,
     RequestTooLarge,
+    RangeNotSatisfiable,
+    GeminiInput,
+    GeminiSensitiveInput,
     RateLimit,
     OtherServerError,
     OtherClientError,
@@ -20,12 +28,19 @@ impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Status::Success => write!(f, "success"),
+            Status::NoContent => write!(f, "no_content"),
+            Status::NotModified => write!(f, "not_modified"),
             Status::TemporaryRedirect => write!(f, "temporary_redirect"),
             Status::PermanentRedirect => write!(f, "permanent_redirect"),
             Status::Unauthenticated => write!(f, "unauthenticated"),
             Status::Unauthorized => write!(f, "unauthorized"),
             Status::NotFound => write!(f, "not_found"),
+            Status::Gone => write!(f, "gone"),
+            Status::MethodNotAllowed => write!(f, "method_not_allowed"),
             Status::RequestTooLarge => write!(f, "request_too_large"),
+            Status::RangeNotSatisfiable => write!(f, "range_not_satisfiable"),
+            Status::GeminiInput => write!(f, "gemini_input"),
+            Status::GeminiSensitiveInput => write!(f, "gemini_sensitive_input"),
             Status::RateLimit => write!(f, "rate_limited"),
             Status::OtherServerError => write!(f, "other_server_error"),
             Status::OtherClientError => write!(f, "other_client_error"),
@@ -42,12 +57,19 @@ impl FromStr for Status {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "success" => Ok(Status::Success),
+            "no_content" => Ok(Status::NoContent),
+            "not_modified" => Ok(Status::NotModified),
             "temporary_redirect" => Ok(Status::TemporaryRedirect),
             "permanent_redirect" => Ok(Status::PermanentRedirect),
             "unauthenticated" => Ok(Status::Unauthenticated),
             "unauthorized" => Ok(Status::Unauthorized),
             "not_found" => Ok(Status::NotFound),
+            "gone" => Ok(Status::Gone),
+            "method_not_allowed" => Ok(Status::MethodNotAllowed),
             "request_too_large" => Ok(Status::RequestTooLarge),
+            "range_not_satisfiable" => Ok(Status::RangeNotSatisfiable),
+            "gemini_input" => Ok(Status::GeminiInput),
+            "gemini_sensitive_input" => Ok(Status::GeminiSensitiveInput),
             "rate_limited" => Ok(Status::RateLimit),
             "other_server_error" => Ok(Status::OtherServerError),
             "other_client_error" => Ok(Status::OtherClientError),
@@ -56,13 +78,25 @@ impl FromStr for Status {
     }
 }
 
+#[derive(Clone)]
+pub enum ResponseBody {
+    Bytes(Vec<u8>),
+    // Streamed from the file as it's written rather than being read into memory
+    File { path: PathBuf, len: u64 },
+}
+
 #[derive(Clone)]
 pub struct Response {
     status: Status,
     media_type: String,
     redirect_uri: String,
-    body: Vec<u8>,
+    input_prompt: String,
+    body: ResponseBody,
     cacheable: bool,
+    etag: Option<String>,
+    last_modified: Option<DateTime<Utc>>,
+    cache_max_age_seconds: Option<u32>,
+    accept_ranges: bool,
 }
 
 impl Response {
@@ -71,8 +105,13 @@ impl Response {
             status: status,
             media_type: media_type.to_string(),
             redirect_uri: "".to_string(),
-            body: body.to_vec(),
+            input_prompt: "".to_string(),
+            body: ResponseBody::Bytes(body.to_vec()),
             cacheable: cacheable,
+            etag: None,
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
         }
     }
 
@@ -81,19 +120,83 @@ impl Response {
             status: status,
             media_type: "".to_string(),
             redirect_uri: redirect_uri.to_string(),
-            body: Vec::new(),
+            input_prompt: "".to_string(),
+            body: ResponseBody::Bytes(Vec::new()),
+            cacheable: false,
+            etag: None,
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
+        }
+    }
+
+    // Gemini only, the prompt is sent in place of the media type
+    pub fn new_with_input_prompt(status: Status, input_prompt: &str) -> Response {
+        Response {
+            status: status,
+            media_type: "".to_string(),
+            redirect_uri: "".to_string(),
+            input_prompt: input_prompt.to_string(),
+            body: ResponseBody::Bytes(Vec::new()),
             cacheable: false,
+            etag: None,
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
+        }
+    }
+
+    pub fn new_streamed_file(media_type: &str, path: PathBuf, len: u64) -> Response {
+        Response {
+            status: Status::Success,
+            media_type: media_type.to_string(),
+            redirect_uri: "".to_string(),
+            input_prompt: "".to_string(),
+            body: ResponseBody::File {
+                path: path,
+                len: len,
+            },
+            cacheable: true,
+            etag: None,
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
+        }
+    }
+
+    pub fn new_no_content() -> Response {
+        Response {
+            status: Status::NoContent,
+            media_type: "".to_string(),
+            redirect_uri: "".to_string(),
+            input_prompt: "".to_string(),
+            body: ResponseBody::Bytes(Vec::new()),
+            cacheable: false,
+            etag: None,
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
+        }
+    }
+
+    pub fn new_not_modified(etag: &str) -> Response {
+        Response {
+            status: Status::NotModified,
+            media_type: "".to_string(),
+            redirect_uri: "".to_string(),
+            input_prompt: "".to_string(),
+            body: ResponseBody::Bytes(Vec::new()),
+            cacheable: true,
+            etag: Some(etag.to_string()),
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
         }
     }
 
     pub fn new_for_request_and_status(request: &mut Request, status: Status) -> Response {
         for try_ext in request.protocol().media_type_file_extensions() {
-            let try_path = format!(
-                "{}/{}.{}",
-                request.server_context().config().errdocs_path(),
-                status,
-                try_ext
-            );
+            let try_path = format!("{}/{}.{}", request.errdocs_path(), status, try_ext);
 
             match try_load_file_for_path(&try_path, request) {
                 Ok(response) => {
@@ -101,8 +204,13 @@ impl Response {
                         status: status,
                         media_type: response.media_type().to_owned(),
                         redirect_uri: "".to_string(),
-                        body: response.body().to_vec(),
+                        input_prompt: "".to_string(),
+                        body: response.body_source().clone(),
                         cacheable: false,
+                        etag: None,
+                        last_modified: None,
+                        cache_max_age_seconds: None,
+                        accept_ranges: false,
                     }
                 }
                 Err(_) => {}
@@ -113,20 +221,65 @@ impl Response {
             status: status,
             media_type: "text/plain".to_string(),
             redirect_uri: "".to_string(),
-            body: match status {
-                Status::Success => "Success",
-                Status::TemporaryRedirect => "Temporary redirect",
-                Status::PermanentRedirect => "Permanent redirect",
-                Status::Unauthenticated => "Unauthenticated",
-                Status::Unauthorized => "Unauthorized",
-                Status::NotFound => "Not found",
-                Status::RequestTooLarge => "Request too large",
-                Status::RateLimit => "Rate limited",
-                Status::OtherServerError => "Other server error",
-                Status::OtherClientError => "Other client error",
-            }
-            .into(),
+            input_prompt: "".to_string(),
+            body: ResponseBody::Bytes(
+                match status {
+                    Status::Success => "Success",
+                    Status::NoContent => "No content",
+                    Status::NotModified => "Not modified",
+                    Status::TemporaryRedirect => "Temporary redirect",
+                    Status::PermanentRedirect => "Permanent redirect",
+                    Status::Unauthenticated => "Unauthenticated",
+                    Status::Unauthorized => "Unauthorized",
+                    Status::NotFound => "Not found",
+                    Status::Gone => "Gone",
+                    Status::MethodNotAllowed => "Method not allowed",
+                    Status::RequestTooLarge => "Request too large",
+                    Status::RangeNotSatisfiable => "Range not satisfiable",
+                    Status::GeminiInput => "Input required",
+                    Status::GeminiSensitiveInput => "Sensitive input required",
+                    Status::RateLimit => "Rate limited",
+                    Status::OtherServerError => "Other server error",
+                    Status::OtherClientError => "Other client error",
+                }
+                .into(),
+            ),
             cacheable: false,
+            etag: None,
+            last_modified: None,
+            cache_max_age_seconds: None,
+            accept_ranges: false,
+        }
+    }
+
+    pub fn with_etag(self, etag: &str) -> Response {
+        Response {
+            etag: Some(etag.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_last_modified(self, last_modified: DateTime<Utc>) -> Response {
+        Response {
+            last_modified: Some(last_modified),
+            ..self
+        }
+    }
+
+    // Overrides the default Cache-Control max-age for cacheable responses
+    pub fn with_cache_max_age_seconds(self, cache_max_age_seconds: u32) -> Response {
+        Response {
+            cacheable: true,
+            cache_max_age_seconds: Some(cache_max_age_seconds),
+            ..self
+        }
+    }
+
+    // Only static files (not rendered templates) can be requested in parts
+    pub fn with_accept_ranges(self) -> Response {
+        Response {
+            accept_ranges: true,
+            ..self
         }
     }
 
@@ -142,11 +295,39 @@ impl Response {
         &self.redirect_uri
     }
 
+    pub fn input_prompt(&self) -> &str {
+        &self.input_prompt
+    }
+
+    // Empty for streamed files, which are never rendered or compressed
     pub fn body(&self) -> &[u8] {
+        match &self.body {
+            ResponseBody::Bytes(body) => body,
+            ResponseBody::File { .. } => &[],
+        }
+    }
+
+    pub fn body_source(&self) -> &ResponseBody {
         &self.body
     }
 
     pub fn cacheable(&self) -> bool {
         self.cacheable
     }
+
+    pub fn cache_max_age_seconds(&self) -> Option<u32> {
+        self.cache_max_age_seconds
+    }
+
+    pub fn accept_ranges(&self) -> bool {
+        self.accept_ranges
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    pub fn last_modified(&self) -> Option<&DateTime<Utc>> {
+        self.last_modified.as_ref()
+    }
 }
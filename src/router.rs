@@ -1,19 +1,166 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Utc};
 use log::{error, info};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use walkdir::WalkDir;
 
-use crate::files::try_load_file_for_path;
+use crate::config::TrailingSlashPolicy;
+use crate::feed::{
+    generate_gemsub_feed, generate_sitemap, ATOM_FEED_MEDIA_TYPE, ATOM_FEED_PATH, GEMINI_FEED_PATH,
+    GEMSUB_FEED_PATH, SITEMAP_CACHE_MAX_AGE_SECONDS, SITEMAP_MEDIA_TYPE, SITEMAP_PATH,
+};
+use crate::files::{has_blocked_file_extension, is_hidden_path, try_load_file_for_path};
+use crate::metrics::METRICS_MEDIA_TYPE;
 use crate::protocol::Protocol;
+use crate::redirects::find_redirect;
 use crate::request::Request;
 use crate::response::{Response, Status};
-use crate::templates::{render_markdown_response_for_request, Markup};
+use crate::templates::{
+    render_directory_listing_for_request, render_markdown_response_for_request,
+    DirectoryListingEntry, Markup,
+};
+
+const WELL_KNOWN_URI_PREFIX: &str = "/.well-known/";
+const ROBOTS_TXT_PATH: &str = "/robots.txt";
+const ROBOTS_TXT_MEDIA_TYPE: &str = "text/plain; charset=utf-8";
+
+// Everything except unreserved characters, so names can't end a Gemini link or add a query/fragment
+const PATH_SEGMENT_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
 
 pub fn route_request(request: &mut Request) -> Response {
-    let os_path_str = format!(
-        "{}{}",
-        request.server_context().config().public_root_path(),
-        request.path()
-    );
+    if request.protocol() == Protocol::Https
+        && !["GET", "HEAD", "OPTIONS"].contains(&request.method())
+    {
+        error!(
+            "[{}] [{}] [{}] [{}] {} (method: {})",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            Status::MethodNotAllowed,
+            request.method(),
+        );
+        return Response::new_for_request_and_status(request, Status::MethodNotAllowed);
+    }
+
+    let response = route_request_for_path(request);
+
+    // CORS preflight requests are routed like a GET so front matter overrides apply, but get no body
+    match (request.method(), *response.status()) {
+        ("OPTIONS", Status::Success | Status::NotModified) => Response::new_no_content(),
+        _ => response,
+    }
+}
+
+fn route_request_for_path(request: &mut Request) -> Response {
+    // Not logged so frequent scrapes don't flood the access log
+    if request.protocol() == Protocol::Https
+        && request.server_context().config().metrics_path() == Some(request.path())
+    {
+        return Response::new(
+            Status::Success,
+            METRICS_MEDIA_TYPE,
+            request.server_context().metrics().render().as_bytes(),
+            false,
+        );
+    }
+
+    if request.protocol() == Protocol::Https && request.path().starts_with(WELL_KNOWN_URI_PREFIX) {
+        match try_route_well_known_request(request) {
+            Some(response) => return response,
+            None => {}
+        }
+    }
+
+    let generated_response = match (request.protocol(), request.path()) {
+        (Protocol::Gemini, GEMSUB_FEED_PATH | GEMINI_FEED_PATH) => Some(Response::new(
+            Status::Success,
+            &Protocol::Gemini.media_type(),
+            generate_gemsub_feed(request.server_context()).as_bytes(),
+            false,
+        )),
+        (Protocol::Https, ATOM_FEED_PATH) => Some(Response::new(
+            Status::Success,
+            ATOM_FEED_MEDIA_TYPE,
+            request
+                .server_context()
+                .get_atom_feed_xml(Protocol::Https)
+                .as_bytes(),
+            false,
+        )),
+        (Protocol::Https, SITEMAP_PATH) => Some(
+            Response::new(
+                Status::Success,
+                SITEMAP_MEDIA_TYPE,
+                generate_sitemap(request.server_context()).as_bytes(),
+                true,
+            )
+            .with_cache_max_age_seconds(SITEMAP_CACHE_MAX_AGE_SECONDS),
+        ),
+        (_, ROBOTS_TXT_PATH) => request
+            .server_context()
+            .config()
+            .robots_txt()
+            .map(|robots_txt| {
+                Response::new(
+                    Status::Success,
+                    ROBOTS_TXT_MEDIA_TYPE,
+                    robots_txt.as_bytes(),
+                    false,
+                )
+            }),
+        _ => None,
+    };
+
+    if let Some(response) = generated_response {
+        info!(
+            "[{}] [{}] [{}] [{}] {} (generated)",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            response.status(),
+        );
+
+        return response;
+    }
+
+    if let Some((status, redirect_uri)) =
+        find_redirect(request.server_context().redirect_rules(), request.path())
+    {
+        info!(
+            "[{}] [{}] [{}] [{}] {} (redirect to {})",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            status,
+            redirect_uri,
+        );
+
+        return Response::new_with_redirect_uri(status, &redirect_uri);
+    }
+
+    if let Some(redirect_uri) = trailing_slash_redirect_uri(request) {
+        info!(
+            "[{}] [{}] [{}] [{}] {} (trailing slash redirect to {})",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            Status::PermanentRedirect,
+            redirect_uri,
+        );
+
+        return Response::new_with_redirect_uri(Status::PermanentRedirect, &redirect_uri);
+    }
+
+    let os_path_str = format!("{}{}", request.public_root_path(), request.path());
     let path_buf = PathBuf::from(&os_path_str);
 
     let is_directory = path_buf.is_dir();
@@ -82,6 +229,10 @@ pub fn route_request(request: &mut Request) -> Response {
                 None => {}
             }
         }
+
+        if request.server_context().config().enable_directory_listing() {
+            return route_directory_listing(&os_path_str, request);
+        }
     } else {
         // First try exact requested path UNLESS .md file extension which gets handled later
         if !os_path_str.ends_with(".md") {
@@ -116,7 +267,7 @@ pub fn route_request(request: &mut Request) -> Response {
                         error!(
                             "[{}] [{}] [{}] [{}] {} (from file: {})",
                             request.protocol(),
-                            request.peer_addr(),
+                            request.client_ip(),
                             request.client_certificate_details(),
                             request.path(),
                             status,
@@ -131,11 +282,10 @@ pub fn route_request(request: &mut Request) -> Response {
     }
 
     // whelp, we tried our best :c
-    // TODO: directory listing if is_directory?
     error!(
         "[{}] [{}] [{}] [{}] {}",
         request.protocol(),
-        request.peer_addr(),
+        request.client_ip(),
         request.client_certificate_details(),
         request.path(),
         Status::NotFound,
@@ -143,6 +293,40 @@ pub fn route_request(request: &mut Request) -> Response {
     return Response::new_for_request_and_status(request, Status::NotFound);
 }
 
+// Returns where to redirect to if the path doesn't match TRAILING_SLASH_POLICY
+fn trailing_slash_redirect_uri(request: &Request) -> Option<String> {
+    trailing_slash_redirect_path(
+        request.path(),
+        request.server_context().config().trailing_slash_policy(),
+        request.public_root_path(),
+    )
+}
+
+fn trailing_slash_redirect_path(
+    path: &str,
+    trailing_slash_policy: TrailingSlashPolicy,
+    public_root_path: &str,
+) -> Option<String> {
+    match trailing_slash_policy {
+        TrailingSlashPolicy::Add => {
+            let last_segment = path.rsplit('/').next().unwrap_or("");
+            if path.ends_with('/') || last_segment.contains('.') {
+                return None;
+            }
+
+            match PathBuf::from(format!("{}{}", public_root_path, path)).is_dir() {
+                true => Some(format!("{}/", path)),
+                false => None,
+            }
+        }
+        TrailingSlashPolicy::Remove => match path.strip_suffix('/') {
+            Some(stripped_path) if !stripped_path.is_empty() => Some(stripped_path.to_string()),
+            _ => None,
+        },
+        TrailingSlashPolicy::None => None,
+    }
+}
+
 // Tries to load a file, if it exists it will return a response with the contents or the error loading/rendering them
 fn try_route_request_for_path(try_path: &str, request: &mut Request) -> Option<Response> {
     match try_load_file_for_path(try_path, request) {
@@ -150,7 +334,7 @@ fn try_route_request_for_path(try_path: &str, request: &mut Request) -> Option<R
             info!(
                 "[{}] [{}] [{}] [{}] {} (from file: {})",
                 request.protocol(),
-                request.peer_addr(),
+                request.client_ip(),
                 request.client_certificate_details(),
                 request.path(),
                 response.status(),
@@ -164,7 +348,7 @@ fn try_route_request_for_path(try_path: &str, request: &mut Request) -> Option<R
                 error!(
                     "[{}] [{}] [{}] [{}] {} (from file: {})",
                     request.protocol(),
-                    request.peer_addr(),
+                    request.client_ip(),
                     request.client_certificate_details(),
                     request.path(),
                     status,
@@ -175,3 +359,245 @@ fn try_route_request_for_path(try_path: &str, request: &mut Request) -> Option<R
         },
     }
 }
+
+// Lists the direct children of a directory that has no index file
+fn route_directory_listing(os_path_str: &str, request: &mut Request) -> Response {
+    let path_buf = match PathBuf::from(os_path_str).canonicalize() {
+        Ok(path_buf) => path_buf,
+        Err(_) => return Response::new_for_request_and_status(request, Status::NotFound),
+    };
+
+    if !path_buf.starts_with(request.public_root_path()) {
+        error!(
+            "[{}] [{}] [{}] [{}] {}: canonicalized directory not in public root dir - path traversal attempt? (canonicalized path: {})",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            Status::OtherClientError,
+            os_path_str
+        );
+        return Response::new_for_request_and_status(request, Status::OtherClientError);
+    }
+
+    let config = request.server_context().config();
+    let hidden_files_blocked = config.hidden_files_blocked();
+    if hidden_files_blocked
+        && is_hidden_path(
+            path_buf
+                .strip_prefix(request.public_root_path())
+                .unwrap_or(path_buf.as_path()),
+        )
+    {
+        return Response::new_for_request_and_status(request, Status::NotFound);
+    }
+
+    let base_uri_path = match request.path().ends_with("/") {
+        true => request.path().to_string(),
+        false => format!("{}/", request.path()),
+    };
+
+    let mut entries = WalkDir::new(&path_buf)
+        .min_depth(1)
+        .max_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let name = entry.file_name().to_str()?.to_string();
+            if hidden_files_blocked && name.starts_with('.') {
+                return None;
+            }
+
+            // Entries that would be refused aren't listed
+            if has_blocked_file_extension(Path::new(&name), config.blocked_file_extensions()) {
+                return None;
+            }
+
+            // Templates are listed under the path they are rendered at
+            let name = match metadata.is_dir() {
+                true => name,
+                false => match name.strip_suffix(".hbs") {
+                    Some(rendered_name) if !rendered_name.is_empty() => rendered_name.to_string(),
+                    _ => name,
+                },
+            };
+
+            // The request path is already percent-encoded, but the name isn't
+            let encoded_name = utf8_percent_encode(&name, PATH_SEGMENT_ENCODE_SET);
+
+            Some(DirectoryListingEntry {
+                path: match metadata.is_dir() {
+                    true => format!("{}{}/", base_uri_path, encoded_name),
+                    false => format!("{}{}", base_uri_path, encoded_name),
+                },
+                name: name,
+                is_directory: metadata.is_dir(),
+                size: metadata.len(),
+                modified_at: metadata
+                    .modified()
+                    .ok()
+                    .map(|modified| DateTime::<Utc>::from(modified)),
+            })
+        })
+        .collect::<Vec<DirectoryListingEntry>>();
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries.dedup_by(|a, b| a.name == b.name);
+
+    match render_directory_listing_for_request(request, &entries) {
+        Ok(response) => {
+            info!(
+                "[{}] [{}] [{}] [{}] {} (directory listing: {})",
+                request.protocol(),
+                request.client_ip(),
+                request.client_certificate_details(),
+                request.path(),
+                response.status(),
+                os_path_str,
+            );
+            response
+        }
+        Err(status) => Response::new_for_request_and_status(request, status),
+    }
+}
+
+// Serves files verbatim from WELL_KNOWN_PATH (i.e. for ACME challenges), falling through to normal routing if missing
+// or if WELL_KNOWN_PATH isn't set
+fn try_route_well_known_request(request: &mut Request) -> Option<Response> {
+    let well_known_path = request.server_context().config().well_known_path()?;
+    let os_path_str = format!(
+        "{}/{}",
+        well_known_path,
+        request
+            .path()
+            .strip_prefix(WELL_KNOWN_URI_PREFIX)
+            .unwrap_or("")
+    );
+
+    let path_buf = match PathBuf::from(&os_path_str).canonicalize() {
+        Ok(path_buf) => path_buf,
+        Err(_) => return None,
+    };
+
+    if !path_buf.starts_with(format!("{}/", well_known_path)) {
+        error!(
+            "[{}] [{}] [{}] [{}] {}: canonicalized path not in well-known dir - path traversal attempt? (canonicalized path: {})",
+            request.protocol(),
+            request.client_ip(),
+            request.client_certificate_details(),
+            request.path(),
+            Status::OtherClientError,
+            os_path_str
+        );
+        return Some(Response::new_for_request_and_status(
+            request,
+            Status::OtherClientError,
+        ));
+    }
+
+    if !path_buf.is_file() {
+        return None;
+    }
+
+    match request.server_context().fs_read(path_buf) {
+        Ok(file) => {
+            let response = Response::new(
+                Status::Success,
+                mime_guess::from_path(&os_path_str)
+                    .first_raw()
+                    .unwrap_or("application/octet-stream"),
+                file.data(),
+                false,
+            );
+
+            info!(
+                "[{}] [{}] [{}] [{}] {} (from well-known file: {})",
+                request.protocol(),
+                request.client_ip(),
+                request.client_certificate_details(),
+                request.path(),
+                response.status(),
+                os_path_str,
+            );
+
+            Some(response)
+        }
+        Err(_) => Some(Response::new_for_request_and_status(
+            request,
+            Status::Unauthorized,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The crate's own folder stands in for the public root, as it has an src/ directory in it
+    const PUBLIC_ROOT_PATH: &str = env!("CARGO_MANIFEST_DIR");
+
+    fn redirect_path(path: &str, trailing_slash_policy: TrailingSlashPolicy) -> Option<String> {
+        trailing_slash_redirect_path(path, trailing_slash_policy, PUBLIC_ROOT_PATH)
+    }
+
+    #[test]
+    fn trailing_slash_root_path_is_never_redirected() {
+        assert_eq!(redirect_path("/", TrailingSlashPolicy::Add), None);
+        assert_eq!(redirect_path("/", TrailingSlashPolicy::Remove), None);
+        assert_eq!(redirect_path("/", TrailingSlashPolicy::None), None);
+    }
+
+    #[test]
+    fn trailing_slash_add_redirects_existing_directories() {
+        assert_eq!(
+            redirect_path("/src", TrailingSlashPolicy::Add),
+            Some("/src/".to_string())
+        );
+        assert_eq!(redirect_path("/src/", TrailingSlashPolicy::Add), None);
+    }
+
+    #[test]
+    fn trailing_slash_add_ignores_paths_with_extensions() {
+        assert_eq!(
+            redirect_path("/src/main.rs", TrailingSlashPolicy::Add),
+            None
+        );
+        assert_eq!(redirect_path("/Cargo.toml", TrailingSlashPolicy::Add), None);
+    }
+
+    #[test]
+    fn trailing_slash_add_ignores_nonexistent_paths() {
+        assert_eq!(
+            redirect_path("/nonexistent", TrailingSlashPolicy::Add),
+            None
+        );
+        assert_eq!(
+            redirect_path("/src/nonexistent", TrailingSlashPolicy::Add),
+            None
+        );
+    }
+
+    #[test]
+    fn trailing_slash_remove_strips_the_slash_whether_or_not_the_path_exists() {
+        assert_eq!(
+            redirect_path("/src/", TrailingSlashPolicy::Remove),
+            Some("/src".to_string())
+        );
+        assert_eq!(
+            redirect_path("/nonexistent/", TrailingSlashPolicy::Remove),
+            Some("/nonexistent".to_string())
+        );
+        assert_eq!(
+            redirect_path("/src/main.rs", TrailingSlashPolicy::Remove),
+            None
+        );
+    }
+
+    #[test]
+    fn trailing_slash_none_never_redirects() {
+        assert_eq!(redirect_path("/src", TrailingSlashPolicy::None), None);
+        assert_eq!(redirect_path("/src/", TrailingSlashPolicy::None), None);
+    }
+}
@@ -1,22 +1,107 @@
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine as _;
+use chrono::{DateTime, FixedOffset, Locale, NaiveDate, Utc};
 use handlebars::{
-    to_json, Context, Decorator, Handlebars, Helper, HelperDef, HelperResult, JsonRender, Output,
-    RenderContext, RenderError, RenderErrorReason, ScopedJson,
+    to_json, BlockContext, Context, Decorator, Handlebars, Helper, HelperDef, HelperResult,
+    JsonRender, Output, RenderContext, RenderError, RenderErrorReason, Renderable, ScopedJson,
 };
 use handlebars_chrono::HandlebarsChronoDateTime;
-use log::error;
+use log::{debug, error};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use rand::seq::{IteratorRandom as _, SliceRandom};
 use serde_with::{DeserializeFromStr, SerializeDisplay};
-use std::fmt;
-use std::net::SocketAddr;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Weak;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::context::PageMetadata;
+use crate::context::{CachedFile, PageMetadata, ServerContext};
 use crate::protocol::Protocol;
 use crate::request::Request;
 use crate::response::{Response, Status};
 
 pub const DEFAULT_BLANK_PARTIAL_NAME: &str = "blank";
 
+const DEFAULT_READING_WORDS_PER_MINUTE: u64 = 200;
+
+const CACHE_BUST_HASH_LENGTH: usize = 8;
+
+// Tags replaced with a space when stripping HTML so the text either side doesn't run together
+const STRIP_HTML_BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+// Encodes URL-safe with padding, but decodes with or without padding
+const BASE64_URL_SAFE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+// Also accepted when decoding as data files often use the standard alphabet
+const BASE64_STANDARD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const DIRECTORY_LISTING_HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Index of {{path}}</title>
+</head>
+<body>
+<h1>Index of {{path}}</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>
+{{#each entries}}<tr><td><a href="{{path}}">{{name}}{{#if is_directory}}/{{/if}}</a></td><td>{{#unless is_directory}}{{size}}{{/unless}}</td><td>{{modified_at}}</td></tr>
+{{/each}}</table>
+</body>
+</html>
+"#;
+
+const DIRECTORY_LISTING_GEMTEXT_TEMPLATE: &str = r#"# Index of {{{path}}}
+
+{{#each entries}}=> {{{path}}} {{{name}}}{{#if is_directory}}/{{else}} ({{size}} bytes){{/if}}
+{{/each}}"#;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
 pub enum Markup {
     Html,
@@ -78,16 +163,40 @@ pub struct TemplateRequestContext {
     pub meta: serde_json::Value,
     pub data: serde_json::Value,
     pub posts: Vec<PageMetadata>,
+    pub tags: Vec<String>,
+    pub next_post: serde_json::Value,
+    pub prev_post: serde_json::Value,
     pub peer_addr: SocketAddr,
+    pub client_ip: IpAddr,
+    pub method: String,
+    pub hostname: String,
     pub path: String,
+    pub query: serde_json::Value,
+    pub gemini_query: Option<String>,
     pub is_authenticated: bool,
     pub is_anonymous: bool,
     pub common_name: String,
+    pub cert_serial: Option<String>,
+    pub cert_not_after: Option<DateTime<Utc>>,
+    pub cert_sans: Vec<String>,
+    pub cert_organization: Option<String>,
+    pub cert_fingerprint: Option<String>,
     pub protocol: Protocol,
     pub markup: Markup,
     pub is_gemini: bool,
     pub is_https: bool,
     pub os_platform: String,
+    // Only known once the page has rendered, so only set for its layout
+    pub word_count: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+pub struct DirectoryListingEntry {
+    pub name: String,
+    pub path: String,
+    pub is_directory: bool,
+    pub size: u64,
+    pub modified_at: Option<DateTime<Utc>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -96,20 +205,85 @@ struct TemplateResponseContext {
     media_type: Option<String>,
     redirect_uri: Option<String>,
     redirect_permanent: Option<bool>,
+    gemini_input_prompt: Option<String>,
+    gemini_input_sensitive: Option<bool>,
+    auth_prompt: Option<String>,
 }
 
-pub fn initialize_handlebars(handlebars: &mut Handlebars) {
+pub fn initialize_handlebars(handlebars: &mut Handlebars, server_context: Weak<ServerContext>) {
     handlebars.register_helper("datetime", Box::new(HandlebarsChronoDateTime));
     handlebars.register_helper(
         "private-context-serialize",
         Box::new(serialize_context_helper),
     );
     handlebars.register_helper("pick-random", Box::new(pick_random_helper));
+    handlebars.register_helper("pick-n-random", Box::new(pick_n_random_helper));
     handlebars.register_helper("partial-for-markup", Box::new(partial_for_markup_helper));
+    handlebars.register_helper(
+        "each-posts-tagged-all",
+        Box::new(each_posts_tagged_all_helper),
+    );
+    handlebars.register_helper(
+        "each-posts-tagged-any",
+        Box::new(each_posts_tagged_any_helper),
+    );
+    handlebars.register_helper("each-reverse", Box::new(each_reverse_helper));
+    handlebars.register_helper("each-post", Box::new(each_post_helper));
+    handlebars.register_helper("each-sibling", Box::new(each_sibling_helper));
+    handlebars.register_helper("with-data", Box::new(with_data_helper));
+    handlebars.register_helper("if-protocol", Box::new(if_protocol_helper));
+    handlebars.register_helper("require-auth", Box::new(require_auth_helper));
+    handlebars.register_helper(
+        "file-modified-at",
+        Box::new(file_modified_at_helper {
+            server_context: server_context.clone(),
+        }),
+    );
+    handlebars.register_helper(
+        "cache-bust",
+        Box::new(cache_bust_helper {
+            server_context: server_context.clone(),
+        }),
+    );
+    handlebars.register_helper(
+        "sha256",
+        Box::new(sha256_helper {
+            server_context: server_context.clone(),
+        }),
+    );
+    handlebars.register_helper("format-date", Box::new(format_date_helper));
+    handlebars.register_helper("truncate", Box::new(truncate_helper));
+    handlebars.register_helper("slug", Box::new(slug_helper));
+    handlebars.register_helper("table-of-contents", Box::new(table_of_contents_helper));
+    handlebars.register_helper("render-toc", Box::new(render_toc_helper));
+    handlebars.register_helper("reading-time", Box::new(reading_time_helper));
+    handlebars.register_helper("word-count", Box::new(word_count_helper));
+    handlebars.register_helper("sort-by", Box::new(sort_by_helper));
+    handlebars.register_helper("filter-by", Box::new(filter_by_helper));
+    handlebars.register_helper("filter-by-truthy", Box::new(filter_by_truthy_helper));
+    handlebars.register_helper("group-by", Box::new(group_by_helper));
+    handlebars.register_helper("url-encode", Box::new(url_encode_helper));
+    handlebars.register_helper("url-decode", Box::new(url_decode_helper));
+    handlebars.register_helper("base64-encode", Box::new(base64_encode_helper));
+    handlebars.register_helper("base64-decode", Box::new(base64_decode_helper));
+    handlebars.register_helper("markdown", Box::new(markdown_helper));
+    handlebars.register_helper("strip-html", Box::new(strip_html_helper));
+    handlebars.register_helper("strip-gemtext", Box::new(strip_gemtext_helper));
+    handlebars.register_helper("excerpt", Box::new(excerpt_helper));
+    handlebars.register_helper("paginate", Box::new(paginate_helper));
     handlebars.register_decorator("temporary-redirect", Box::new(temporary_redirect_decorator));
     handlebars.register_decorator("permanent-redirect", Box::new(permanent_redirect_decorator));
     handlebars.register_decorator("status", Box::new(status_decorator));
     handlebars.register_decorator("media-type", Box::new(media_type_decorator));
+    handlebars.register_decorator("gemini-input", Box::new(gemini_input_decorator));
+    handlebars.register_decorator(
+        "gemini-sensitive-input",
+        Box::new(gemini_sensitive_input_decorator),
+    );
+    handlebars.register_decorator(
+        "require-client-cert",
+        Box::new(require_client_cert_decorator),
+    );
 }
 
 pub fn render_response_body_for_request(
@@ -122,6 +296,33 @@ pub fn render_response_body_for_request(
     match String::from_utf8(body) {
         Ok(template_body) => match render_template(request, &template_body) {
             Ok((rendered_body, response_context)) => {
+                // Input prompts are ignored for HTTPS so the page renders normally
+                if let (Protocol::Gemini, Some(input_prompt)) =
+                    (request.protocol(), &response_context.gemini_input_prompt)
+                {
+                    let status = match response_context.gemini_input_sensitive {
+                        Some(true) => Status::GeminiSensitiveInput,
+                        _ => Status::GeminiInput,
+                    };
+
+                    return Ok(Response::new_with_input_prompt(status, input_prompt));
+                }
+
+                // The prompt replaces the default meta of the Gemini 60 response
+                if let Some(auth_prompt) = &response_context.auth_prompt {
+                    return Ok(match request.protocol() {
+                        Protocol::Gemini => {
+                            Response::new_with_input_prompt(Status::Unauthenticated, auth_prompt)
+                        }
+                        Protocol::Https => Response::new(
+                            Status::Unauthenticated,
+                            request.template_context().markup.media_type().as_str(),
+                            &[],
+                            false,
+                        ),
+                    });
+                }
+
                 let status = match response_context.status {
                     Some(status_str) => match Status::from_str(&status_str) {
                         Ok(status) => status,
@@ -129,7 +330,7 @@ pub fn render_response_body_for_request(
                             error!(
                                   "[{}] [{}] [{}] [{}] Handlebars error in {}: status set to unknown status code {}",
                                   request.protocol(),
-                                  request.peer_addr(),
+                                  request.client_ip(),
                                   request.client_certificate_details(),
                                   request.path(),
                                   loaded_path,
@@ -168,7 +369,7 @@ pub fn render_response_body_for_request(
                 error!(
                     "[{}] [{}] [{}] [{}] Handlebars error in {}: {}",
                     request.protocol(),
-                    request.peer_addr(),
+                    request.client_ip(),
                     request.client_certificate_details(),
                     request.path(),
                     loaded_path,
@@ -181,7 +382,7 @@ pub fn render_response_body_for_request(
             error!(
                 "[{}] [{}] [{}] [{}] Unicode error reading {} (valid up to {})",
                 request.protocol(),
-                request.peer_addr(),
+                request.client_ip(),
                 request.client_certificate_details(),
                 request.path(),
                 loaded_path,
@@ -192,6 +393,111 @@ pub fn render_response_body_for_request(
     }
 }
 
+pub fn render_directory_listing_for_request(
+    request: &Request,
+    entries: &[DirectoryListingEntry],
+) -> Result<Response, Status> {
+    let template_string = match request.protocol() {
+        Protocol::Gemini => DIRECTORY_LISTING_GEMTEXT_TEMPLATE,
+        Protocol::Https => DIRECTORY_LISTING_HTML_TEMPLATE,
+    };
+
+    match request.server_context().handlebars_render_template(
+        template_string,
+        &serde_json::json!({
+            "path": request.path(),
+            "entries": entries,
+        }),
+    ) {
+        Ok(rendered_body) => Ok(Response::new(
+            Status::Success,
+            &request.protocol().media_type(),
+            rendered_body.as_bytes(),
+            false,
+        )),
+        Err(err) => {
+            error!(
+                "[{}] [{}] [{}] [{}] Handlebars error in directory listing: {}",
+                request.protocol(),
+                request.client_ip(),
+                request.client_certificate_details(),
+                request.path(),
+                err
+            );
+            Err(Status::OtherServerError)
+        }
+    }
+}
+
+// Wraps a rendered page in the partial named by `layout` in its front matter, with the page as `body`
+pub fn render_layout_for_request(
+    loaded_path: &str,
+    request: &Request,
+    response: Response,
+) -> Result<Response, Status> {
+    let layout = match request
+        .template_context()
+        .meta
+        .get("layout")
+        .and_then(|layout| layout.as_str())
+    {
+        Some(layout) if !layout.is_empty() => layout,
+        _ => return Ok(response),
+    };
+
+    // Redirects, input prompts and require-auth responses have no body to wrap
+    if !response.redirect_uri().is_empty()
+        || !response.input_prompt().is_empty()
+        || (*response.status() == Status::Unauthenticated && response.body().is_empty())
+    {
+        return Ok(response);
+    }
+
+    let mut layout_context = match serde_json::to_value(request.template_context()) {
+        Ok(layout_context) => layout_context,
+        Err(err) => {
+            error!(
+                "[{}] [{}] [{}] [{}] Error serializing layout context for {}: {}",
+                request.protocol(),
+                request.client_ip(),
+                request.client_certificate_details(),
+                request.path(),
+                loaded_path,
+                err
+            );
+            return Err(Status::OtherServerError);
+        }
+    };
+    let body = String::from_utf8_lossy(response.body()).into_owned();
+    layout_context["word_count"] = serde_json::Value::from(count_unicode_words(&body));
+    layout_context["body"] = serde_json::Value::String(body);
+
+    match request
+        .server_context()
+        .handlebars_render_partial(layout, &layout_context)
+    {
+        Ok(rendered_body) => Ok(Response::new(
+            *response.status(),
+            response.media_type(),
+            rendered_body.as_bytes(),
+            response.cacheable(),
+        )),
+        Err(err) => {
+            error!(
+                "[{}] [{}] [{}] [{}] Handlebars error in layout {} for {}: {}",
+                request.protocol(),
+                request.client_ip(),
+                request.client_certificate_details(),
+                request.path(),
+                layout,
+                loaded_path,
+                err
+            );
+            Err(Status::OtherServerError)
+        }
+    }
+}
+
 fn render_template(
     request: &Request,
     template_string: &str,
@@ -215,6 +521,9 @@ fn render_template(
                     media_type: None,
                     redirect_uri: None,
                     redirect_permanent: None,
+                    gemini_input_prompt: None,
+                    gemini_input_sensitive: None,
+                    auth_prompt: None,
                 });
             Ok((rendered_body.to_string(), response_context))
         }
@@ -222,11 +531,37 @@ fn render_template(
     }
 }
 
+// Markdown is returned as-is for Markup::Markdown
+fn markdown_to_markup(markdown_str: &str, markup: Markup) -> Result<String, String> {
+    match markup {
+        Markup::Gemtext => Ok(md2gemtext::convert(markdown_str)),
+        Markup::Html => markdown::to_html_with_options(
+            markdown_str,
+            &markdown::Options {
+                compile: markdown::CompileOptions {
+                    allow_dangerous_html: true,
+                    ..markdown::CompileOptions::default()
+                },
+                ..markdown::Options::default()
+            },
+        )
+        .map_err(|err| err.to_string()),
+        Markup::Markdown => Ok(markdown_str.to_string()),
+    }
+}
+
 pub fn render_markdown_response_for_request(
     request: &Request,
     response: &Response,
     loaded_path: &str,
 ) -> Result<Response, Status> {
+    // Input prompts and require-auth responses have no body to convert
+    if !response.input_prompt().is_empty()
+        || (*response.status() == Status::Unauthenticated && response.body().is_empty())
+    {
+        return Ok(response.clone());
+    }
+
     match String::from_utf8(response.body().to_vec()) {
         Ok(resp_body_str) => {
             // Remove <?POSTPROCESS ... POSTPROCESS?> processing tags used to keep post-processable handlebars calls from being encoded
@@ -237,29 +572,15 @@ pub fn render_markdown_response_for_request(
                     .replace("POSTPROCESS?>", "")
             };
 
-            let rendered_md = match request.template_context().markup {
-                Markup::Gemtext => strip_postprocess_tags(md2gemtext::convert(&resp_body_str)),
-                Markup::Html => match markdown::to_html_with_options(
-                    &resp_body_str,
-                    &markdown::Options {
-                        compile: markdown::CompileOptions {
-                            allow_dangerous_html: true,
-                            ..markdown::CompileOptions::default()
-                        },
-                        ..markdown::Options::default()
-                    },
-                ) {
-                    Ok(str) => {
-                        // Strip AFTER for markdown::to_html_with_options as otherwise handlebars get turned into HTML entities
-                        strip_postprocess_tags(str)
-                    }
+            let rendered_md =
+                match markdown_to_markup(&resp_body_str, request.template_context().markup) {
+                    // Strip AFTER for markdown::to_html_with_options as otherwise handlebars get turned into HTML entities
+                    Ok(str) => strip_postprocess_tags(str),
                     Err(err) => {
                         error!("Error converting markdown to HTML: {}", err);
                         return Err(Status::OtherServerError);
                     }
-                },
-                Markup::Markdown => strip_postprocess_tags(resp_body_str), // Markdown just needs the meta tags stripping...
-            };
+                };
 
             let md_response = Response::new(
                 *response.status(),
@@ -269,7 +590,9 @@ pub fn render_markdown_response_for_request(
             );
 
             match render_response_body_for_request(loaded_path, request, &md_response) {
-                Ok(rerendered_md_resp) => Ok(rerendered_md_resp),
+                Ok(rerendered_md_resp) => {
+                    render_layout_for_request(loaded_path, request, rerendered_md_resp)
+                }
                 Err(status) => Err(status),
             }
         }
@@ -329,6 +652,44 @@ impl HelperDef for pick_random_helper {
     }
 }
 
+#[allow(non_camel_case_types)]
+pub struct pick_n_random_helper;
+
+impl HelperDef for pick_n_random_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("pick-n-random", 0))?;
+        let n = h
+            .param(1)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("pick-n-random", 1))
+            .and_then(|param| {
+                json_value_as_u64(param.value())
+                    .ok_or(RenderErrorReason::InvalidParamType("number"))
+            })? as usize;
+
+        // Objects are picked from by their values
+        let mut pool = match param.value() {
+            serde_json::Value::Array(array) => array.clone(),
+            serde_json::Value::Object(object) => object.values().cloned().collect(),
+            _ => Vec::new(),
+        };
+
+        // partial_shuffle clamps n to the length, so n >= length returns everything shuffled
+        let (picked, _) = pool.partial_shuffle(&mut rand::thread_rng(), n);
+
+        Ok(ScopedJson::Derived(serde_json::Value::Array(
+            picked.to_vec(),
+        )))
+    }
+}
+
 #[allow(non_camel_case_types)]
 pub struct partial_for_markup_helper;
 
@@ -369,90 +730,226 @@ impl HelperDef for partial_for_markup_helper {
     }
 }
 
-fn status_decorator<'reg: 'rc, 'rc>(
-    d: &Decorator,
-    _: &Handlebars,
-    ctx: &Context,
-    rc: &mut RenderContext,
-) -> Result<(), RenderError> {
-    let param = d
-        .param(0)
-        .ok_or(RenderErrorReason::ParamNotFoundForIndex("status", 0))?;
-    let mut new_ctx = match rc.context() {
-        Some(rc_ctx) => rc_ctx.as_ref().clone(),
-        None => ctx.clone(),
-    };
+// Renders the block once per item (like the built-in each) or the else block if there are no items
+fn render_each_block<'reg: 'rc, 'rc>(
+    h: &Helper<'rc>,
+    r: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+    items: &[serde_json::Value],
+) -> HelperResult {
+    let keyed_items = items
+        .iter()
+        .map(|item| (None, item.clone()))
+        .collect::<Vec<(Option<String>, serde_json::Value)>>();
 
-    {
-        let data = new_ctx.data_mut();
-        if let Some(ref mut m) = data.as_object_mut() {
-            m.insert("status".to_string(), to_json(param.value().render()));
+    render_keyed_each_block(h, r, ctx, rc, out, &keyed_items)
+}
+
+// Same as render_each_block but also sets @key for items that came from an object
+fn render_keyed_each_block<'reg: 'rc, 'rc>(
+    h: &Helper<'rc>,
+    r: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+    items: &[(Option<String>, serde_json::Value)],
+) -> HelperResult {
+    if items.is_empty() {
+        if let Some(inverse) = h.inverse() {
+            inverse.render(r, ctx, rc, out)?;
         }
+        return Ok(());
     }
-    rc.set_context(new_ctx);
+
+    if let Some(template) = h.template() {
+        for (index, (key, item)) in items.iter().enumerate() {
+            let mut block = BlockContext::new();
+            block.set_base_value(item.clone());
+            block.set_local_var("index", to_json(index));
+            block.set_local_var("first", to_json(index == 0));
+            block.set_local_var("last", to_json(index == items.len() - 1));
+            if let Some(key) = key {
+                block.set_local_var("key", to_json(key));
+            }
+
+            rc.push_block(block);
+            template.render(r, ctx, rc, out)?;
+            rc.pop_block();
+        }
+    }
+
     Ok(())
 }
 
-fn media_type_decorator<'reg: 'rc, 'rc>(
-    d: &Decorator,
-    _: &Handlebars,
-    ctx: &Context,
-    rc: &mut RenderContext,
-) -> Result<(), RenderError> {
-    let param = d
-        .param(0)
-        .ok_or(RenderErrorReason::ParamNotFoundForIndex("media-type", 0))?;
+fn comma_separated_hash_param(
+    h: &Helper,
+    helper_name: &'static str,
+    param_name: &str,
+) -> Result<Vec<String>, RenderError> {
+    let param = h
+        .hash_get(param_name)
+        .ok_or(RenderErrorReason::ParamNotFoundForName(
+            helper_name,
+            param_name.to_string(),
+        ))?;
 
-    let mut new_ctx = match rc.context() {
-        Some(rc_ctx) => rc_ctx.as_ref().clone(),
-        None => ctx.clone(),
-    };
+    Ok(param
+        .value()
+        .render()
+        .split(',')
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect())
+}
 
-    {
-        let data = new_ctx.data_mut();
-        if let Some(ref mut m) = data.as_object_mut() {
-            m.insert("media_type".to_string(), to_json(param.value().render()));
+fn posts_from_context(ctx: &Context) -> Vec<serde_json::Value> {
+    ctx.data()
+        .get("posts")
+        .and_then(|posts| posts.as_array())
+        .cloned()
+        .unwrap_or_default()
+}
+
+// The path up to and including its last slash, so /blog/ is the parent of both /blog/post and /blog/
+fn parent_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(slash_index) => &path[..=slash_index],
+        None => "",
+    }
+}
+
+fn post_has_tag(post: &serde_json::Value, tag: &str) -> bool {
+    match post.get("tags").and_then(|tags| tags.as_array()) {
+        Some(tags) => tags.iter().any(|post_tag| post_tag.as_str() == Some(tag)),
+        None => false,
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct each_posts_tagged_all_helper;
+
+impl HelperDef for each_posts_tagged_all_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let tags = comma_separated_hash_param(h, "each-posts-tagged-all", "tags")?;
+
+        let posts = posts_from_context(ctx)
+            .into_iter()
+            .filter(|post| tags.iter().all(|tag| post_has_tag(post, tag)))
+            .collect::<Vec<serde_json::Value>>();
+
+        render_each_block(h, r, ctx, rc, out, &posts)
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct each_posts_tagged_any_helper;
+
+impl HelperDef for each_posts_tagged_any_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let tags = comma_separated_hash_param(h, "each-posts-tagged-any", "tags")?;
+
+        let posts = posts_from_context(ctx)
+            .into_iter()
+            .filter(|post| tags.iter().any(|tag| post_has_tag(post, tag)))
+            .collect::<Vec<serde_json::Value>>();
+
+        render_each_block(h, r, ctx, rc, out, &posts)
+    }
+}
+
+// Protocol names are compared case-insensitively, so "gemini" matches Gemini and "https" matches HTTPS
+#[allow(non_camel_case_types)]
+pub struct if_protocol_helper;
+
+impl HelperDef for if_protocol_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let protocol_name = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("if-protocol", 0))?
+            .value()
+            .render();
+
+        let is_protocol = ctx
+            .data()
+            .get("protocol")
+            .and_then(|protocol| protocol.as_str())
+            .map_or(false, |protocol| {
+                protocol.eq_ignore_ascii_case(&protocol_name)
+            });
+
+        let block = match is_protocol {
+            true => h.template(),
+            false => h.inverse(),
+        };
+
+        if let Some(block) = block {
+            block.render(r, ctx, rc, out)?;
         }
+
+        Ok(())
     }
-    rc.set_context(new_ctx);
-    Ok(())
 }
 
-fn temporary_redirect_decorator<'reg: 'rc, 'rc>(
-    d: &Decorator,
-    _: &Handlebars,
-    ctx: &Context,
-    rc: &mut RenderContext,
-) -> Result<(), RenderError> {
-    let param = d.param(0).ok_or(RenderErrorReason::ParamNotFoundForIndex(
-        "temporary-redirect",
-        0,
-    ))?;
-    let mut new_ctx = match rc.context() {
-        Some(rc_ctx) => rc_ctx.as_ref().clone(),
-        None => ctx.clone(),
-    };
-    {
-        let data = new_ctx.data_mut();
-        if let Some(ref mut m) = data.as_object_mut() {
-            m.insert("redirect_permanent".to_string(), to_json(false));
-            m.insert("redirect_uri".to_string(), to_json(param.value().render()));
+// Unauthenticated requests get an empty unauthenticated response instead of the rendered page
+#[allow(non_camel_case_types)]
+pub struct require_auth_helper;
+
+impl HelperDef for require_auth_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let is_authenticated = ctx
+            .data()
+            .get("is_authenticated")
+            .and_then(|is_authenticated| is_authenticated.as_bool())
+            .unwrap_or(false);
+
+        if is_authenticated {
+            if let Some(block) = h.template() {
+                block.render(r, ctx, rc, out)?;
+            }
+            return Ok(());
         }
+
+        let auth_prompt = h
+            .param(0)
+            .map(|param| param.value().render())
+            .unwrap_or_default();
+        set_auth_prompt(ctx, rc, auth_prompt);
+        Ok(())
     }
-    rc.set_context(new_ctx);
-    Ok(())
 }
 
-fn permanent_redirect_decorator<'reg: 'rc, 'rc>(
-    d: &Decorator,
-    _: &Handlebars,
-    ctx: &Context,
-    rc: &mut RenderContext,
-) -> Result<(), RenderError> {
-    let param = d.param(0).ok_or(RenderErrorReason::ParamNotFoundForIndex(
-        "permanent-redirect",
-        0,
-    ))?;
+// Makes the response an empty unauthenticated one, with the prompt used for Gemini if it isn't empty
+fn set_auth_prompt(ctx: &Context, rc: &mut RenderContext, auth_prompt: String) {
     let mut new_ctx = match rc.context() {
         Some(rc_ctx) => rc_ctx.as_ref().clone(),
         None => ctx.clone(),
@@ -461,10 +958,2048 @@ fn permanent_redirect_decorator<'reg: 'rc, 'rc>(
     {
         let data = new_ctx.data_mut();
         if let Some(ref mut m) = data.as_object_mut() {
-            m.insert("redirect_permanent".to_string(), to_json(true));
-            m.insert("redirect_uri".to_string(), to_json(param.value().render()));
+            m.insert(
+                "status".to_string(),
+                to_json(Status::Unauthenticated.to_string()),
+            );
+            m.insert("auth_prompt".to_string(), to_json(auth_prompt));
         }
     }
     rc.set_context(new_ctx);
-    Ok(())
+}
+
+#[allow(non_camel_case_types)]
+pub struct each_reverse_helper;
+
+impl HelperDef for each_reverse_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("each-reverse", 0))?;
+
+        let items = match param.value() {
+            serde_json::Value::Array(array) => array
+                .iter()
+                .rev()
+                .map(|value| (None, value.clone()))
+                .collect::<Vec<(Option<String>, serde_json::Value)>>(),
+            serde_json::Value::Object(object) => object
+                .iter()
+                .rev()
+                .map(|(key, value)| (Some(key.clone()), value.clone()))
+                .collect::<Vec<(Option<String>, serde_json::Value)>>(),
+            _ => Vec::new(),
+        };
+
+        render_keyed_each_block(h, r, ctx, rc, out, &items)
+    }
+}
+
+// Iterates over posts like each, with @odd, @even, @total and @markup set as well
+#[allow(non_camel_case_types)]
+pub struct each_post_helper;
+
+impl HelperDef for each_post_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let posts = posts_from_context(ctx);
+
+        if posts.is_empty() {
+            if let Some(inverse) = h.inverse() {
+                inverse.render(r, ctx, rc, out)?;
+            }
+            return Ok(());
+        }
+
+        let markup = ctx
+            .data()
+            .get("markup")
+            .cloned()
+            .unwrap_or(serde_json::Value::Null);
+
+        if let Some(template) = h.template() {
+            for (index, post) in posts.iter().enumerate() {
+                let mut block = BlockContext::new();
+                block.set_base_value(post.clone());
+                block.set_local_var("index", to_json(index));
+                block.set_local_var("first", to_json(index == 0));
+                block.set_local_var("last", to_json(index == posts.len() - 1));
+                block.set_local_var("odd", to_json(index % 2 == 1));
+                block.set_local_var("even", to_json(index % 2 == 0));
+                block.set_local_var("total", to_json(posts.len()));
+                block.set_local_var("markup", markup.clone());
+
+                rc.push_block(block);
+                template.render(r, ctx, rc, out)?;
+                rc.pop_block();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Posts in the same folder as the requested page (including it), in the same order as posts
+#[allow(non_camel_case_types)]
+pub struct each_sibling_helper;
+
+impl HelperDef for each_sibling_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let request_path = ctx
+            .data()
+            .get("path")
+            .and_then(|path| path.as_str())
+            .unwrap_or("");
+        let request_parent_path = parent_path(request_path);
+
+        let siblings = posts_from_context(ctx)
+            .into_iter()
+            .filter(|post| {
+                post.get("path")
+                    .and_then(|path| path.as_str())
+                    .map_or(false, |path| parent_path(path) == request_parent_path)
+            })
+            .collect::<Vec<serde_json::Value>>();
+
+        render_each_block(h, r, ctx, rc, out, &siblings)
+    }
+}
+
+// Missing and null values render the else block
+#[allow(non_camel_case_types)]
+pub struct with_data_helper;
+
+impl HelperDef for with_data_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let key_path = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("with-data", 0))?
+            .value()
+            .render();
+
+        let value = ctx
+            .data()
+            .get("data")
+            .and_then(|data| json_value_at_key_path(data, &key_path))
+            .filter(|value| !value.is_null());
+
+        match (value, h.template()) {
+            (Some(value), Some(template)) => {
+                let mut block = BlockContext::new();
+                block.set_base_value(value.clone());
+
+                rc.push_block(block);
+                template.render(r, ctx, rc, out)?;
+                rc.pop_block();
+            }
+            (None, _) => {
+                if let Some(inverse) = h.inverse() {
+                    inverse.render(r, ctx, rc, out)?;
+                }
+            }
+            (Some(_), None) => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct file_modified_at_helper {
+    server_context: Weak<ServerContext>,
+}
+
+impl HelperDef for file_modified_at_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let path = h
+            .hash_get("path")
+            .ok_or(RenderErrorReason::ParamNotFoundForName(
+                "file-modified-at",
+                "path".to_string(),
+            ))?
+            .value()
+            .render();
+
+        let modified = self
+            .server_context
+            .upgrade()
+            .and_then(|server_context| {
+                fs_read_request_public_root_path(&server_context, ctx, &path)
+            })
+            .and_then(|file| file.metadata().modified().ok());
+
+        let modified_utc: DateTime<Utc> = match modified {
+            Some(modified) => modified.into(),
+            None => {
+                debug!("file-modified-at: no modified time for {}", path);
+                return Ok(ScopedJson::Derived(serde_json::Value::String("".into())));
+            }
+        };
+
+        let formatted = match h.hash_get("format") {
+            Some(format) => {
+                let mut formatted = String::new();
+                match write!(
+                    formatted,
+                    "{}",
+                    modified_utc.format(&format.value().render())
+                ) {
+                    Ok(_) => formatted,
+                    Err(_) => {
+                        debug!("file-modified-at: invalid format for {}", path);
+                        "".to_string()
+                    }
+                }
+            }
+            None => modified_utc.to_rfc3339(),
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(formatted)))
+    }
+}
+
+// Reads a file from the public root of the request being rendered (i.e. its virtual host's)
+fn fs_read_request_public_root_path(
+    server_context: &ServerContext,
+    ctx: &Context,
+    path: &str,
+) -> Option<CachedFile> {
+    let protocol = ctx
+        .data()
+        .get("protocol")
+        .and_then(|protocol| protocol.as_str())
+        .and_then(|protocol| Protocol::from_str(protocol).ok())?;
+    let hostname = ctx
+        .data()
+        .get("hostname")
+        .and_then(|hostname| hostname.as_str())
+        .unwrap_or("");
+
+    server_context
+        .fs_read_public_root_path(protocol, hostname, path)
+        .ok()
+}
+
+// Appends a version query parameter from the file's content hash, so its URL changes when it does
+#[allow(non_camel_case_types)]
+pub struct cache_bust_helper {
+    server_context: Weak<ServerContext>,
+}
+
+impl HelperDef for cache_bust_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let path = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("cache-bust", 0))?
+            .value()
+            .render();
+
+        let file = self.server_context.upgrade().and_then(|server_context| {
+            fs_read_request_public_root_path(&server_context, ctx, &path)
+        });
+
+        let busted_path = match file {
+            Some(file) => {
+                let hash = file.content_hash();
+                format!(
+                    "{}?v={}",
+                    path,
+                    &hash[..CACHE_BUST_HASH_LENGTH.min(hash.len())]
+                )
+            }
+            None => {
+                debug!("cache-bust: could not read {}", path);
+                path
+            }
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(busted_path)))
+    }
+}
+
+// Returns a Subresource Integrity hash (i.e. "sha256-...") of a file or a string
+#[allow(non_camel_case_types)]
+pub struct sha256_helper {
+    server_context: Weak<ServerContext>,
+}
+
+impl HelperDef for sha256_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let digest = match (h.hash_get("path"), h.param(0)) {
+            (Some(path), _) => {
+                let path = path.value().render();
+                let file = self.server_context.upgrade().and_then(|server_context| {
+                    fs_read_request_public_root_path(&server_context, ctx, &path)
+                });
+
+                match file {
+                    Some(file) => Sha256::digest(file.data()),
+                    None => {
+                        debug!("sha256: could not read {}", path);
+                        return Ok(ScopedJson::Derived(serde_json::Value::String("".into())));
+                    }
+                }
+            }
+            (None, Some(param)) => Sha256::digest(param.value().render().as_bytes()),
+            (None, None) => {
+                return Err(RenderErrorReason::ParamNotFoundForIndex("sha256", 0).into());
+            }
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(format!(
+            "sha256-{}",
+            BASE64_STANDARD.encode(digest)
+        ))))
+    }
+}
+
+// RFC 3339 date-times (as front matter dates are serialized) keep their offset, and plain dates
+// (i.e. "2024-01-31") are midnight UTC
+fn parse_date_time(str: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(str.trim()).ok().or_else(|| {
+        NaiveDate::parse_from_str(str.trim(), "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|date_time| date_time.and_utc().fixed_offset())
+    })
+}
+
+#[allow(non_camel_case_types)]
+pub struct format_date_helper;
+
+impl HelperDef for format_date_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let date_str = string_param_or_empty(h, 0);
+        let format = string_param_or_empty(h, 1);
+
+        let date_time = match parse_date_time(&date_str) {
+            Some(date_time) => date_time,
+            None => {
+                debug!("format-date: invalid date {}", date_str);
+                return Ok(ScopedJson::Derived(serde_json::Value::String("".into())));
+            }
+        };
+
+        let locale = match h.param(2).map(|param| param.value().render()) {
+            Some(locale_str) => match Locale::try_from(locale_str.as_str()) {
+                Ok(locale) => Some(locale),
+                Err(_) => {
+                    debug!("format-date: unknown locale {}", locale_str);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // Invalid formats are only caught when written, rather than panicking like to_string
+        let mut formatted = String::new();
+        let result = match locale {
+            Some(locale) => write!(formatted, "{}", date_time.format_localized(&format, locale)),
+            None => write!(formatted, "{}", date_time.format(&format)),
+        };
+
+        let formatted = match result {
+            Ok(_) => formatted,
+            Err(_) => {
+                debug!("format-date: invalid format {}", format);
+                "".to_string()
+            }
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(formatted)))
+    }
+}
+
+// Shortens str to the last whole word within max_len characters followed by "…", or None if it
+// already fits
+fn truncate_str(str: &str, max_len: usize) -> Option<String> {
+    // byte index of the first character past max_len, if there is one
+    let (cut_index, _) = str.char_indices().nth(max_len)?;
+
+    let truncated = &str[..cut_index];
+
+    // back up to the last word boundary unless the cut already falls on one
+    let truncated = if str[cut_index..].starts_with(char::is_whitespace) {
+        truncated
+    } else {
+        match truncated.rfind(char::is_whitespace) {
+            Some(boundary_index) => &truncated[..boundary_index],
+            None => truncated,
+        }
+    };
+
+    Some(format!("{}…", truncated.trim_end()))
+}
+
+#[allow(non_camel_case_types)]
+pub struct truncate_helper;
+
+impl HelperDef for truncate_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let original = match h.param(0) {
+            Some(param) => param.value().clone(),
+            None => return Ok(ScopedJson::Derived(serde_json::Value::Null)),
+        };
+
+        let (str, max_len) = match (
+            original.as_str(),
+            h.param(1).and_then(|param| param.value().as_u64()),
+        ) {
+            (Some(str), Some(max_len)) => (str, max_len as usize),
+            _ => return Ok(ScopedJson::Derived(original)),
+        };
+
+        match truncate_str(str, max_len) {
+            Some(truncated) => Ok(ScopedJson::Derived(serde_json::Value::String(truncated))),
+            None => Ok(ScopedJson::Derived(original)),
+        }
+    }
+}
+
+// Lowercased alphanumeric runs joined by hyphens, with accents folded to ASCII where possible
+// (i.e. "Crème Brûlée!" => "creme-brulee") and other alphanumerics (i.e. CJK) kept as-is
+fn slugify(str: &str) -> String {
+    let mut slug = String::new();
+    let mut pending_separator = false;
+
+    for c in str.nfc() {
+        let folded = if c.is_ascii_alphanumeric() {
+            c.to_ascii_lowercase().to_string()
+        } else if c.is_alphanumeric() {
+            let ascii_folded = c
+                .to_string()
+                .nfd()
+                .filter(|c| !is_combining_mark(*c))
+                .filter(|c| c.is_ascii_alphanumeric())
+                .map(|c| c.to_ascii_lowercase())
+                .collect::<String>();
+
+            match ascii_folded.is_empty() {
+                true => c.to_lowercase().collect::<String>(),
+                false => ascii_folded,
+            }
+        } else {
+            pending_separator = true;
+            continue;
+        };
+
+        if pending_separator && !slug.is_empty() {
+            slug.push('-');
+        }
+        pending_separator = false;
+        slug.push_str(&folded);
+    }
+
+    slug
+}
+
+#[allow(non_camel_case_types)]
+pub struct slug_helper;
+
+impl HelperDef for slug_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("slug", 0))?;
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(slugify(
+            &param.value().render(),
+        ))))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TocHeading {
+    text: String,
+    anchor: String,
+    level: usize,
+}
+
+// Markdown and gemtext headings (i.e. "## Usage") outside of preformatted blocks, in order. Anchors
+// are slugs of the heading text, with -1, -2 etc. appended to repeats so they stay unique
+fn toc_headings(content: &str) -> Vec<TocHeading> {
+    let mut headings: Vec<TocHeading> = Vec::new();
+    let mut anchor_counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut preformatted = false;
+
+    for line in content.lines() {
+        let line = line.trim_start();
+        if line.starts_with("```") || line.starts_with("~~~") {
+            preformatted = !preformatted;
+            continue;
+        }
+
+        let level = line.chars().take_while(|c| *c == '#').count();
+        if preformatted || level == 0 || level > 6 {
+            continue;
+        }
+
+        let text = match line[level..].strip_prefix(|c: char| c.is_whitespace()) {
+            // Markdown allows an optional closing sequence, i.e. "## Usage ##"
+            Some(text) => match text.trim_end().trim_end_matches('#') {
+                trimmed if trimmed.is_empty() || trimmed.ends_with(char::is_whitespace) => {
+                    trimmed.trim()
+                }
+                _ => text.trim(),
+            },
+            None => continue,
+        };
+
+        let slug = slugify(text);
+        if text.is_empty() || slug.is_empty() {
+            continue;
+        }
+
+        let count = anchor_counts.entry(slug.clone()).or_insert(0);
+        let anchor = match *count {
+            0 => slug,
+            n => format!("{}-{}", slug, n),
+        };
+        *count += 1;
+
+        headings.push(TocHeading {
+            text: text.to_string(),
+            anchor: anchor,
+            level: level,
+        });
+    }
+
+    headings
+}
+
+// Nested lists where a heading can only be one level deeper than the one before it, so skipped
+// levels (i.e. "#" followed by "###") don't leave empty list items
+fn render_toc(headings: &[TocHeading], markup: Markup) -> String {
+    let base_level = headings.iter().map(|h| h.level).min().unwrap_or(1);
+    let mut rendered = String::new();
+    let mut depth = 0;
+
+    for heading in headings {
+        let level = (heading.level - base_level + 1).min(depth + 1);
+
+        match markup {
+            Markup::Html => {
+                if level > depth {
+                    rendered.push_str("<ul>");
+                } else {
+                    rendered.push_str("</li>");
+                    for _ in level..depth {
+                        rendered.push_str("</ul></li>");
+                    }
+                }
+
+                let _ = write!(
+                    rendered,
+                    "<li><a href=\"#{}\">{}</a>",
+                    heading.anchor,
+                    handlebars::html_escape(&heading.text)
+                );
+            }
+            // Gemtext can't nest, so every heading is a link line
+            Markup::Gemtext => {
+                let _ = writeln!(rendered, "=> #{} {}", heading.anchor, heading.text);
+            }
+            Markup::Markdown => {
+                let _ = writeln!(
+                    rendered,
+                    "{}- [{}](#{})",
+                    "  ".repeat(level - 1),
+                    heading.text,
+                    heading.anchor
+                );
+            }
+        }
+
+        depth = level;
+    }
+
+    if let Markup::Html = markup {
+        for _ in 0..depth {
+            rendered.push_str("</li></ul>");
+        }
+    }
+
+    rendered
+}
+
+#[allow(non_camel_case_types)]
+pub struct table_of_contents_helper;
+
+impl HelperDef for table_of_contents_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let content = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex(
+                "table-of-contents",
+                0,
+            ))?
+            .value()
+            .render();
+
+        Ok(ScopedJson::Derived(to_json(toc_headings(&content))))
+    }
+}
+
+// Writes straight to the output so the rendered markup isn't escaped
+#[allow(non_camel_case_types)]
+pub struct render_toc_helper;
+
+impl HelperDef for render_toc_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let content = string_param_or_empty(h, 0);
+
+        let markup = ctx
+            .data()
+            .get("markup")
+            .and_then(|markup| markup.as_str())
+            .and_then(|markup| Markup::from_str(markup).ok())
+            .unwrap_or(Markup::Html);
+
+        out.write(&render_toc(&toc_headings(&content), markup))?;
+
+        Ok(())
+    }
+}
+
+// Removes Handlebars expressions and HTML tags, and reduces gemtext link lines to their labels
+fn strip_markup(str: &str) -> String {
+    let mut stripped = String::new();
+
+    for line in str.lines() {
+        let line = match line.strip_prefix("=>") {
+            Some(link) => {
+                let link = link.trim_start();
+                match link.find(char::is_whitespace) {
+                    Some(label_index) => &link[label_index..],
+                    None => "",
+                }
+            }
+            None => line,
+        };
+
+        let mut rest = line;
+        while !rest.is_empty() {
+            let (open, close) = match (rest.find("{{"), rest.find('<')) {
+                (Some(hbs_index), Some(tag_index)) if tag_index < hbs_index => (tag_index, ">"),
+                (Some(hbs_index), _) => (hbs_index, "}}"),
+                (None, Some(tag_index)) => (tag_index, ">"),
+                (None, None) => {
+                    stripped.push_str(rest);
+                    break;
+                }
+            };
+
+            stripped.push_str(&rest[..open]);
+            stripped.push(' ');
+            rest = match rest[open..].find(close) {
+                Some(close_index) => &rest[open + close_index + close.len()..],
+                None => "",
+            };
+        }
+
+        stripped.push('\n');
+    }
+
+    stripped
+}
+
+fn count_words(str: &str) -> usize {
+    strip_markup(str)
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .count()
+}
+
+// Counts words by Unicode word boundaries, so i.e. CJK text is counted per character
+fn count_unicode_words(str: &str) -> usize {
+    strip_markup(str).unicode_words().count()
+}
+
+#[allow(non_camel_case_types)]
+pub struct reading_time_helper;
+
+impl HelperDef for reading_time_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let content = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("reading-time", 0))?
+            .value()
+            .render();
+
+        let words_per_minute = match h.param(1).and_then(|param| param.value().as_u64()) {
+            Some(words_per_minute) if words_per_minute > 0 => words_per_minute,
+            _ => DEFAULT_READING_WORDS_PER_MINUTE,
+        };
+
+        let words = count_words(&content) as u64;
+        let minutes = (words + words_per_minute - 1) / words_per_minute;
+
+        Ok(ScopedJson::Derived(serde_json::Value::from(minutes)))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct word_count_helper;
+
+impl HelperDef for word_count_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let content = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("word-count", 0))?
+            .value()
+            .render();
+
+        Ok(ScopedJson::Derived(serde_json::Value::from(
+            count_unicode_words(&content),
+        )))
+    }
+}
+
+// Looks up a dot-separated key path (i.e. "author.name") in a JSON value
+fn json_value_at_key_path<'a>(
+    value: &'a serde_json::Value,
+    key_path: &str,
+) -> Option<&'a serde_json::Value> {
+    key_path
+        .split('.')
+        .try_fold(value, |value, key| match value {
+            serde_json::Value::Object(object) => object.get(key),
+            serde_json::Value::Array(array) => key.parse::<usize>().ok().and_then(|i| array.get(i)),
+            _ => None,
+        })
+}
+
+// Numbers sort before strings, which sort before any other types
+fn compare_json_values(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+    fn type_rank(value: &serde_json::Value) -> u8 {
+        match value {
+            serde_json::Value::Number(_) => 0,
+            serde_json::Value::String(_) => 1,
+            serde_json::Value::Bool(_) => 2,
+            _ => 3,
+        }
+    }
+
+    match (a, b) {
+        (serde_json::Value::Number(a), serde_json::Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (serde_json::Value::String(a), serde_json::Value::String(b)) => a.cmp(b),
+        (serde_json::Value::Bool(a), serde_json::Value::Bool(b)) => a.cmp(b),
+        (a, b) => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct sort_by_helper;
+
+impl HelperDef for sort_by_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let array = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("sort-by", 0))?
+            .value()
+            .as_array()
+            .ok_or(RenderErrorReason::InvalidParamType("array"))?;
+        let key_path = h
+            .param(1)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("sort-by", 1))?
+            .value()
+            .render();
+        let reverse = h
+            .param(2)
+            .and_then(|param| param.value().as_bool())
+            .unwrap_or(false);
+
+        let mut sorted = array.clone();
+
+        // sort_by is stable, and items missing the key always sort last (even when reversed)
+        sorted.sort_by(|a, b| {
+            match (
+                json_value_at_key_path(a, &key_path),
+                json_value_at_key_path(b, &key_path),
+            ) {
+                (Some(a), Some(b)) => match reverse {
+                    true => compare_json_values(b, a),
+                    false => compare_json_values(a, b),
+                },
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        });
+
+        Ok(ScopedJson::Derived(serde_json::Value::Array(sorted)))
+    }
+}
+
+// Same truthiness as the built-in if helper
+fn json_value_is_truthy(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Null => false,
+        serde_json::Value::Bool(bool) => *bool,
+        serde_json::Value::Number(number) => number.as_f64().map_or(false, |n| n != 0.0),
+        serde_json::Value::String(string) => !string.is_empty(),
+        serde_json::Value::Array(array) => !array.is_empty(),
+        serde_json::Value::Object(_) => true,
+    }
+}
+
+// Numbers compare by value so i.e. 1 and 1.0 are equal
+fn json_values_equal(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+    match (a, b) {
+        (serde_json::Value::Number(a), serde_json::Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (a, b) => a == b,
+    }
+}
+
+fn filter_array_by<F: Fn(Option<&serde_json::Value>) -> bool>(
+    h: &Helper,
+    helper_name: &'static str,
+    predicate: F,
+) -> Result<serde_json::Value, RenderError> {
+    let key_path = h
+        .param(1)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex(helper_name, 1))?
+        .value()
+        .render();
+
+    // Always an array (even if the param isn't one) so the result can go straight into each
+    let filtered = match h
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex(helper_name, 0))?
+        .value()
+    {
+        serde_json::Value::Array(array) => array
+            .iter()
+            .filter(|item| predicate(json_value_at_key_path(item, &key_path)))
+            .cloned()
+            .collect::<Vec<serde_json::Value>>(),
+        _ => Vec::new(),
+    };
+
+    Ok(serde_json::Value::Array(filtered))
+}
+
+#[allow(non_camel_case_types)]
+pub struct filter_by_helper;
+
+impl HelperDef for filter_by_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let value = h
+            .param(2)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("filter-by", 2))?
+            .value();
+
+        Ok(ScopedJson::Derived(filter_array_by(
+            h,
+            "filter-by",
+            |item_value| match (item_value, value) {
+                // Array keys (i.e. post tags) match if any of their values is equal
+                (Some(serde_json::Value::Array(item_values)), value) if !value.is_array() => {
+                    item_values
+                        .iter()
+                        .any(|item_value| json_values_equal(item_value, value))
+                }
+                (Some(item_value), value) => json_values_equal(item_value, value),
+                (None, _) => false,
+            },
+        )?))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct filter_by_truthy_helper;
+
+impl HelperDef for filter_by_truthy_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        Ok(ScopedJson::Derived(filter_array_by(
+            h,
+            "filter-by-truthy",
+            |item_value| item_value.map_or(false, json_value_is_truthy),
+        )?))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct group_by_helper;
+
+impl HelperDef for group_by_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let array = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("group-by", 0))?
+            .value()
+            .as_array()
+            .ok_or(RenderErrorReason::InvalidParamType("array"))?;
+        let key_path = h
+            .param(1)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("group-by", 1))?
+            .value()
+            .render();
+
+        // BTreeMap so groups are always ordered by key
+        let mut groups = BTreeMap::<String, Vec<serde_json::Value>>::new();
+
+        for item in array {
+            let group_values = match json_value_at_key_path(item, &key_path) {
+                Some(serde_json::Value::Array(values)) => values.iter().collect(),
+                Some(value) => vec![value],
+                None => Vec::new(),
+            };
+
+            for group_value in group_values {
+                let group_key = match group_value {
+                    serde_json::Value::Null
+                    | serde_json::Value::Array(_)
+                    | serde_json::Value::Object(_) => continue,
+                    value => value.render(),
+                };
+
+                groups.entry(group_key).or_default().push(item.clone());
+            }
+        }
+
+        Ok(ScopedJson::Derived(serde_json::Value::Object(
+            groups
+                .into_iter()
+                .map(|(key, items)| (key, serde_json::Value::Array(items)))
+                .collect(),
+        )))
+    }
+}
+
+// Missing and null params are treated as an empty string
+fn string_param_or_empty(h: &Helper, index: usize) -> String {
+    match h.param(index).map(|param| param.value()) {
+        None | Some(serde_json::Value::Null) => "".to_string(),
+        Some(value) => value.render(),
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct url_encode_helper;
+
+impl HelperDef for url_encode_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            utf8_percent_encode(&string_param_or_empty(h, 0), NON_ALPHANUMERIC).to_string(),
+        )))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct url_decode_helper;
+
+impl HelperDef for url_decode_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            percent_decode_str(&string_param_or_empty(h, 0))
+                .decode_utf8_lossy()
+                .to_string(),
+        )))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct base64_encode_helper;
+
+impl HelperDef for base64_encode_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("base64-encode", 0))?;
+
+        // Arrays are treated as bytes, anything else as a string
+        let bytes = match param.value() {
+            serde_json::Value::Array(array) => array
+                .iter()
+                .map(|byte| match byte.as_u64() {
+                    Some(byte) if byte <= u8::MAX as u64 => Ok(byte as u8),
+                    _ => Err(RenderErrorReason::InvalidParamType("array of bytes")),
+                })
+                .collect::<Result<Vec<u8>, RenderErrorReason>>()?,
+            value => value.render().into_bytes(),
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            BASE64_URL_SAFE.encode(bytes),
+        )))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct base64_decode_helper;
+
+impl HelperDef for base64_decode_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let encoded = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("base64-decode", 0))?
+            .value()
+            .render();
+        let encoded = encoded.trim();
+
+        let decoded = match BASE64_URL_SAFE
+            .decode(encoded)
+            .or_else(|_| BASE64_STANDARD.decode(encoded))
+        {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    error!("base64-decode: decoded value is not UTF-8: {}", err);
+                    "".to_string()
+                }
+            },
+            Err(err) => {
+                error!("base64-decode: invalid base64: {}", err);
+                "".to_string()
+            }
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(decoded)))
+    }
+}
+
+// Writes straight to the output so the rendered markup isn't escaped
+#[allow(non_camel_case_types)]
+pub struct markdown_helper;
+
+impl HelperDef for markdown_helper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let markdown_str = string_param_or_empty(h, 0);
+
+        let markup = ctx
+            .data()
+            .get("markup")
+            .and_then(|markup| markup.as_str())
+            .and_then(|markup| Markup::from_str(markup).ok())
+            .unwrap_or(Markup::Html);
+
+        let rendered = match markdown_to_markup(&markdown_str, markup) {
+            Ok(rendered) => rendered,
+            Err(err) => {
+                error!("markdown: error converting markdown: {}", err);
+                return Ok(());
+            }
+        };
+
+        // md2gemtext surrounds the output with blank lines which are unwanted inline
+        let rendered = match markup {
+            Markup::Gemtext => rendered.trim_matches('\n').to_string(),
+            _ => rendered,
+        };
+
+        out.write(&rendered)?;
+
+        Ok(())
+    }
+}
+
+enum StripHtmlState {
+    Text,
+    // Tag name so far, and the quote character if inside a quoted attribute value
+    Tag(String, Option<char>),
+    Comment,
+}
+
+// Single pass over the characters so malformed input can't cause any backtracking
+fn strip_html(str: &str) -> String {
+    let mut stripped = String::new();
+    let mut state = StripHtmlState::Text;
+    let mut chars = str.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let next = chars.peek().copied();
+
+        state = match state {
+            StripHtmlState::Text => match (c, next) {
+                ('<', Some('!')) if str_starts_with_chars(&chars, "!--") => {
+                    chars.nth(2);
+                    StripHtmlState::Comment
+                }
+                ('<', Some(next))
+                    if next.is_ascii_alphabetic() || ['/', '!', '?'].contains(&next) =>
+                {
+                    StripHtmlState::Tag(String::new(), None)
+                }
+                // i.e. "a < b" isn't a tag
+                _ => {
+                    stripped.push(c);
+                    StripHtmlState::Text
+                }
+            },
+            StripHtmlState::Tag(name, Some(quote)) => match c == quote {
+                true => StripHtmlState::Tag(name, None),
+                false => StripHtmlState::Tag(name, Some(quote)),
+            },
+            StripHtmlState::Tag(mut name, None) => match c {
+                '"' | '\'' => StripHtmlState::Tag(name, Some(c)),
+                '>' => {
+                    let name = name.trim_start_matches('/').trim_end().to_ascii_lowercase();
+                    if STRIP_HTML_BLOCK_TAGS.contains(&name.as_str()) {
+                        stripped.push(' ');
+                    }
+                    StripHtmlState::Text
+                }
+                // Only the tag name is kept (i.e. "br" for <br class="x"/>), with an end tag's /
+                c if c.is_ascii_alphanumeric() || (c == '/' && name.is_empty()) => {
+                    if !name.ends_with(' ') {
+                        name.push(c);
+                    }
+                    StripHtmlState::Tag(name, None)
+                }
+                _ => {
+                    if !name.is_empty() && !name.ends_with(' ') {
+                        name.push(' ');
+                    }
+                    StripHtmlState::Tag(name, None)
+                }
+            },
+            StripHtmlState::Comment => match c == '-' && str_starts_with_chars(&chars, "->") {
+                true => {
+                    chars.nth(1);
+                    StripHtmlState::Text
+                }
+                false => StripHtmlState::Comment,
+            },
+        };
+    }
+
+    unescape_html_entities(stripped.trim())
+}
+
+fn str_starts_with_chars(chars: &std::iter::Peekable<std::str::Chars>, prefix: &str) -> bool {
+    chars.clone().take(prefix.len()).eq(prefix.chars())
+}
+
+// Named entities commonly found in text plus any numeric entity
+fn unescape_html_entities(str: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = str;
+
+    while let Some(amp_index) = rest.find('&') {
+        unescaped.push_str(&rest[..amp_index]);
+        rest = &rest[amp_index..];
+
+        // Entities are short, so don't look too far for the semicolon
+        let entity = rest
+            .char_indices()
+            .take(12)
+            .find(|(_, c)| *c == ';')
+            .map(|(semicolon_index, _)| &rest[1..semicolon_index]);
+
+        let decoded = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            entity => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity
+                    .strip_prefix('#')
+                    .and_then(|decimal| decimal.parse::<u32>().ok())
+                    .and_then(char::from_u32),
+            },
+        });
+
+        match (entity, decoded) {
+            (Some(entity), Some(decoded)) => {
+                unescaped.push(decoded);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// Drops link lines and heading markers, leaving the rest of the gemtext as-is
+fn strip_gemtext(str: &str) -> String {
+    str.lines()
+        .filter(|line| !line.starts_with("=>"))
+        .map(|line| match line.starts_with('#') {
+            true => line.trim_start_matches('#').trim_start(),
+            false => line,
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+// Text of the first non-empty <p> element, or if there aren't any, the first gemtext line that
+// isn't a heading, link or preformatted
+fn excerpt(str: &str) -> String {
+    // ASCII lowercasing keeps byte indices the same as str
+    let lowercase = str.to_ascii_lowercase();
+    let mut rest_index = 0;
+
+    while let Some(open_index) = lowercase[rest_index..].find("<p").map(|i| rest_index + i) {
+        rest_index = open_index + 2;
+
+        // Skip other tags starting with p (i.e. <pre>)
+        if !lowercase[rest_index..].starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            continue;
+        }
+
+        let content_index = match lowercase[rest_index..].find('>') {
+            Some(i) => rest_index + i + 1,
+            None => break,
+        };
+        let close_index = lowercase[content_index..]
+            .find("</p")
+            .map(|i| content_index + i)
+            .unwrap_or(str.len());
+        rest_index = close_index;
+
+        let text = strip_html(&str[content_index..close_index])
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        if !text.is_empty() {
+            return text;
+        }
+    }
+
+    let mut preformatted = false;
+    for line in str.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+
+        let line = line.trim();
+        if !preformatted && !line.is_empty() && !line.starts_with('#') && !line.starts_with("=>") {
+            return line.to_string();
+        }
+    }
+
+    "".to_string()
+}
+
+#[allow(non_camel_case_types)]
+pub struct excerpt_helper;
+
+impl HelperDef for excerpt_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let excerpt = excerpt(&string_param_or_empty(h, 0));
+
+        let excerpt = match h.param(1).and_then(|param| param.value().as_u64()) {
+            Some(max_len) => truncate_str(&excerpt, max_len as usize).unwrap_or(excerpt),
+            None => excerpt,
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(excerpt)))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct strip_html_helper;
+
+impl HelperDef for strip_html_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        Ok(ScopedJson::Derived(serde_json::Value::String(strip_html(
+            &string_param_or_empty(h, 0),
+        ))))
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct strip_gemtext_helper;
+
+impl HelperDef for strip_gemtext_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            strip_gemtext(&string_param_or_empty(h, 0)),
+        )))
+    }
+}
+
+// Numbers or numeric strings (i.e. from the query string)
+fn json_value_as_u64(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_u64(),
+        serde_json::Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub struct paginate_helper;
+
+impl HelperDef for paginate_helper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg>, RenderError> {
+        let array = match h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("paginate", 0))?
+            .value()
+        {
+            serde_json::Value::Array(array) => array.clone(),
+            _ => Vec::new(),
+        };
+
+        // Missing or invalid page numbers (i.e. no query string) are the first page
+        let current_page = h
+            .param(1)
+            .and_then(|param| json_value_as_u64(param.value()))
+            .unwrap_or(1)
+            .max(1);
+
+        let page_size = h
+            .param(2)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("paginate", 2))
+            .map(|param| json_value_as_u64(param.value()))?
+            .filter(|page_size| *page_size > 0)
+            .ok_or(RenderErrorReason::InvalidParamType("positive page size"))?;
+
+        let total_pages = (array.len() as u64 + page_size - 1) / page_size;
+
+        let items = array
+            .into_iter()
+            .skip(((current_page - 1).saturating_mul(page_size)) as usize)
+            .take(page_size as usize)
+            .collect::<Vec<serde_json::Value>>();
+
+        Ok(ScopedJson::Derived(serde_json::json!({
+            "items": items,
+            "total_pages": total_pages,
+            "current_page": current_page,
+            "has_next": current_page < total_pages,
+            "has_prev": current_page > 1,
+            "next_page": current_page + 1,
+            "prev_page": current_page - 1,
+        })))
+    }
+}
+
+fn status_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let param = d
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("status", 0))?;
+    let mut new_ctx = match rc.context() {
+        Some(rc_ctx) => rc_ctx.as_ref().clone(),
+        None => ctx.clone(),
+    };
+
+    {
+        let data = new_ctx.data_mut();
+        if let Some(ref mut m) = data.as_object_mut() {
+            m.insert("status".to_string(), to_json(param.value().render()));
+        }
+    }
+    rc.set_context(new_ctx);
+    Ok(())
+}
+
+fn media_type_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let param = d
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("media-type", 0))?;
+
+    let mut new_ctx = match rc.context() {
+        Some(rc_ctx) => rc_ctx.as_ref().clone(),
+        None => ctx.clone(),
+    };
+
+    {
+        let data = new_ctx.data_mut();
+        if let Some(ref mut m) = data.as_object_mut() {
+            m.insert("media_type".to_string(), to_json(param.value().render()));
+        }
+    }
+    rc.set_context(new_ctx);
+    Ok(())
+}
+
+fn temporary_redirect_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let param = d.param(0).ok_or(RenderErrorReason::ParamNotFoundForIndex(
+        "temporary-redirect",
+        0,
+    ))?;
+    let mut new_ctx = match rc.context() {
+        Some(rc_ctx) => rc_ctx.as_ref().clone(),
+        None => ctx.clone(),
+    };
+    {
+        let data = new_ctx.data_mut();
+        if let Some(ref mut m) = data.as_object_mut() {
+            m.insert("redirect_permanent".to_string(), to_json(false));
+            m.insert("redirect_uri".to_string(), to_json(param.value().render()));
+        }
+    }
+    rc.set_context(new_ctx);
+    Ok(())
+}
+
+fn permanent_redirect_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let param = d.param(0).ok_or(RenderErrorReason::ParamNotFoundForIndex(
+        "permanent-redirect",
+        0,
+    ))?;
+    let mut new_ctx = match rc.context() {
+        Some(rc_ctx) => rc_ctx.as_ref().clone(),
+        None => ctx.clone(),
+    };
+
+    {
+        let data = new_ctx.data_mut();
+        if let Some(ref mut m) = data.as_object_mut() {
+            m.insert("redirect_permanent".to_string(), to_json(true));
+            m.insert("redirect_uri".to_string(), to_json(param.value().render()));
+        }
+    }
+    rc.set_context(new_ctx);
+    Ok(())
+}
+
+fn set_gemini_input_prompt(
+    d: &Decorator,
+    ctx: &Context,
+    rc: &mut RenderContext,
+    decorator_name: &'static str,
+    sensitive: bool,
+) -> Result<(), RenderError> {
+    let param = d
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex(decorator_name, 0))?;
+    let mut new_ctx = match rc.context() {
+        Some(rc_ctx) => rc_ctx.as_ref().clone(),
+        None => ctx.clone(),
+    };
+
+    {
+        let data = new_ctx.data_mut();
+        if let Some(ref mut m) = data.as_object_mut() {
+            m.insert("gemini_input_sensitive".to_string(), to_json(sensitive));
+            m.insert(
+                "gemini_input_prompt".to_string(),
+                to_json(param.value().render()),
+            );
+        }
+    }
+    rc.set_context(new_ctx);
+    Ok(())
+}
+
+fn gemini_input_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    set_gemini_input_prompt(d, ctx, rc, "gemini-input", false)
+}
+
+fn gemini_sensitive_input_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    set_gemini_input_prompt(d, ctx, rc, "gemini-sensitive-input", true)
+}
+
+// Gemini only, as HTTPS pages can show their own message with require-auth or is_anonymous
+fn require_client_cert_decorator<'reg: 'rc, 'rc>(
+    d: &Decorator,
+    _: &Handlebars,
+    ctx: &Context,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let param = d.param(0).ok_or(RenderErrorReason::ParamNotFoundForIndex(
+        "require-client-cert",
+        0,
+    ))?;
+
+    let is_anonymous_gemini_request = ctx.data().get("is_gemini") == Some(&to_json(true))
+        && ctx.data().get("is_anonymous") == Some(&to_json(true));
+
+    if is_anonymous_gemini_request {
+        set_auth_prompt(ctx, rc, param.value().render());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render_with_helper<H: HelperDef + Send + Sync + 'static>(
+        name: &str,
+        helper: H,
+        template: &str,
+        data: serde_json::Value,
+    ) -> String {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper(name, Box::new(helper));
+        handlebars.render_template(template, &data).unwrap()
+    }
+
+    #[test]
+    fn truncate_str_leaves_short_strings_alone() {
+        assert_eq!(truncate_str("hello world", 11), None);
+        assert_eq!(truncate_str("héllo wörld", 20), None);
+        assert_eq!(truncate_str("", 0), None);
+    }
+
+    #[test]
+    fn truncate_str_backs_up_to_a_word_boundary() {
+        assert_eq!(
+            truncate_str("hello wonderful world", 10),
+            Some("hello…".to_string())
+        );
+        assert_eq!(truncate_str("hello world", 5), Some("hello…".to_string()));
+        assert_eq!(truncate_str("hello   world", 7), Some("hello…".to_string()));
+    }
+
+    #[test]
+    fn truncate_str_never_cuts_inside_a_multibyte_character() {
+        assert_eq!(
+            truncate_str("héllo wörld ünïcode", 8),
+            Some("héllo…".to_string())
+        );
+        assert_eq!(truncate_str("crème brûlée", 11), Some("crème…".to_string()));
+        assert_eq!(truncate_str("🦀🦀 🦀🦀", 4), Some("🦀🦀…".to_string()));
+    }
+
+    #[test]
+    fn truncate_str_cuts_text_without_spaces_at_a_character_boundary() {
+        assert_eq!(
+            truncate_str("日本語のテキスト", 3),
+            Some("日本語…".to_string())
+        );
+    }
+
+    #[test]
+    fn truncate_helper_returns_the_original_for_bad_params() {
+        let data = json!({"text": "hello wonderful world", "number": 42});
+
+        assert_eq!(
+            render_with_helper(
+                "truncate",
+                truncate_helper,
+                "{{truncate text 10}}",
+                data.clone()
+            ),
+            "hello…"
+        );
+        assert_eq!(
+            render_with_helper(
+                "truncate",
+                truncate_helper,
+                "{{truncate text}}",
+                data.clone()
+            ),
+            "hello wonderful world"
+        );
+        assert_eq!(
+            render_with_helper(
+                "truncate",
+                truncate_helper,
+                "{{truncate text \"10\"}}",
+                data.clone()
+            ),
+            "hello wonderful world"
+        );
+        assert_eq!(
+            render_with_helper("truncate", truncate_helper, "{{truncate number 1}}", data),
+            "42"
+        );
+    }
+
+    #[test]
+    fn reading_time_is_zero_without_words() {
+        for content in [
+            "",
+            "   \n ",
+            "<p></p>",
+            "{{title}}",
+            "=> gemini://localhost/",
+        ] {
+            assert_eq!(
+                render_with_helper(
+                    "reading-time",
+                    reading_time_helper,
+                    "{{reading-time content}}",
+                    json!({ "content": content }),
+                ),
+                "0"
+            );
+        }
+    }
+
+    #[test]
+    fn reading_time_defaults_to_200_words_per_minute() {
+        let reading_time = |content: String, template: &str| {
+            render_with_helper(
+                "reading-time",
+                reading_time_helper,
+                template,
+                json!({ "content": content }),
+            )
+        };
+
+        assert_eq!(
+            reading_time("word ".repeat(1), "{{reading-time content}}"),
+            "1"
+        );
+        assert_eq!(
+            reading_time("word ".repeat(200), "{{reading-time content}}"),
+            "1"
+        );
+        assert_eq!(
+            reading_time("word ".repeat(201), "{{reading-time content}}"),
+            "2"
+        );
+        assert_eq!(
+            reading_time("word ".repeat(201), "{{reading-time content 0}}"),
+            "2"
+        );
+        assert_eq!(
+            reading_time("word ".repeat(201), "{{reading-time content 100}}"),
+            "3"
+        );
+    }
+
+    #[test]
+    fn reading_time_counts_words_without_markup() {
+        assert_eq!(count_words("plain text with five words"), 5);
+        assert_eq!(
+            count_words("<p>one <em>two</em></p>\n<img src=\"a.png\"/>three"),
+            3
+        );
+        assert_eq!(count_words("{{> header}}\n{{#if x}}one two{{/if}}"), 2);
+        assert_eq!(
+            count_words("# Heading\n=> gemini://localhost/ link label\n* item"),
+            4
+        );
+        assert_eq!(count_words("=> gemini://localhost/\n- — -"), 0);
+    }
+
+    fn sort_by(template: &str, items: serde_json::Value) -> String {
+        render_with_helper(
+            "sort-by",
+            sort_by_helper,
+            template,
+            json!({ "items": items }),
+        )
+    }
+
+    #[test]
+    fn sort_by_sorts_numbers_numerically_and_strings_lexicographically() {
+        let items = json!([{"n": 10}, {"n": 9}, {"n": 1.5}, {"n": -1}]);
+        assert_eq!(
+            sort_by("{{#each (sort-by items \"n\")}}{{n}},{{/each}}", items),
+            "-1,1.5,9,10,"
+        );
+
+        let items = json!([{"s": "b"}, {"s": "B"}, {"s": "a"}, {"s": "10"}, {"s": "9"}]);
+        assert_eq!(
+            sort_by("{{#each (sort-by items \"s\")}}{{s}},{{/each}}", items),
+            "10,9,B,a,b,"
+        );
+    }
+
+    #[test]
+    fn sort_by_is_stable_and_puts_missing_keys_last() {
+        let items = json!([
+            {"name": "c", "meta": {"rank": 10}},
+            {"name": "x"},
+            {"name": "a", "meta": {"rank": 2}},
+            {"name": "y", "meta": {}},
+            {"name": "b", "meta": {"rank": 2}},
+        ]);
+
+        assert_eq!(
+            sort_by(
+                "{{#each (sort-by items \"meta.rank\")}}{{name}},{{/each}}",
+                items.clone()
+            ),
+            "a,b,c,x,y,"
+        );
+        assert_eq!(
+            sort_by(
+                "{{#each (sort-by items \"meta.rank\" true)}}{{name}},{{/each}}",
+                items
+            ),
+            "c,a,b,x,y,"
+        );
+    }
+
+    #[test]
+    fn url_encode_escapes_spaces_unicode_and_query_characters() {
+        let url_encode = |value: serde_json::Value| {
+            render_with_helper(
+                "url-encode",
+                url_encode_helper,
+                "{{url-encode value}}",
+                json!({ "value": value }),
+            )
+        };
+
+        assert_eq!(url_encode(json!("hello world")), "hello%20world");
+        assert_eq!(url_encode(json!("café ✓")), "caf%C3%A9%20%E2%9C%93");
+        assert_eq!(url_encode(json!("🦀")), "%F0%9F%A6%80");
+        // Safe in a path, but not in a query string value
+        assert_eq!(
+            url_encode(json!("a/b?c=d&e#f+g")),
+            "a%2Fb%3Fc%3Dd%26e%23f%2Bg"
+        );
+        assert_eq!(url_encode(json!(null)), "");
+    }
+
+    #[test]
+    fn url_decode_reverses_url_encode() {
+        let url_decode = |value: serde_json::Value| {
+            render_with_helper(
+                "url-decode",
+                url_decode_helper,
+                "{{{url-decode value}}}",
+                json!({ "value": value }),
+            )
+        };
+
+        assert_eq!(url_decode(json!("hello%20world")), "hello world");
+        assert_eq!(url_decode(json!("caf%C3%A9%20%E2%9C%93")), "café ✓");
+        assert_eq!(
+            url_decode(json!("a%2Fb%3Fc%3Dd%26e%23f%2Bg")),
+            "a/b?c=d&e#f+g"
+        );
+        assert_eq!(url_decode(json!("100%")), "100%");
+        assert_eq!(url_decode(json!(null)), "");
+    }
+
+    #[test]
+    fn url_helpers_return_an_empty_string_for_missing_params() {
+        for template in ["{{url-encode}}", "{{url-encode missing}}"] {
+            assert_eq!(
+                render_with_helper("url-encode", url_encode_helper, template, json!({})),
+                ""
+            );
+        }
+
+        for template in ["{{url-decode}}", "{{url-decode missing}}"] {
+            assert_eq!(
+                render_with_helper("url-decode", url_decode_helper, template, json!({})),
+                ""
+            );
+        }
+    }
+
+    fn render_markdown(markup: Markup, markdown_str: &str) -> String {
+        render_with_helper(
+            "markdown",
+            markdown_helper,
+            "{{markdown bio}}",
+            json!({ "markup": markup, "bio": markdown_str }),
+        )
+    }
+
+    #[test]
+    fn markdown_renders_html_without_double_escaping() {
+        assert_eq!(
+            render_markdown(Markup::Html, "Fish & **chips**"),
+            "<p>Fish &amp; <strong>chips</strong></p>"
+        );
+        assert_eq!(
+            render_markdown(Markup::Html, "Already &amp; escaped"),
+            "<p>Already &amp; escaped</p>"
+        );
+    }
+
+    #[test]
+    fn markdown_renders_gemtext_without_surrounding_blank_lines() {
+        let rendered = render_markdown(Markup::Gemtext, "# Bio\n\nFish & chips\n\n");
+
+        assert!(rendered.starts_with("# Bio"), "{:?}", rendered);
+        assert!(rendered.ends_with("Fish & chips"), "{:?}", rendered);
+        assert!(!rendered.contains("&amp;"), "{:?}", rendered);
+    }
+
+    #[test]
+    fn markdown_defaults_to_html_without_a_markup() {
+        assert_eq!(
+            render_with_helper(
+                "markdown",
+                markdown_helper,
+                "{{markdown bio}}",
+                json!({ "bio": "*hi*" })
+            ),
+            "<p><em>hi</em></p>"
+        );
+    }
+
+    #[test]
+    fn strip_html_removes_nested_tags() {
+        assert_eq!(
+            strip_html("<div><p>Hello <strong><em>nested</em></strong> world</p></div>"),
+            "Hello nested world"
+        );
+        assert_eq!(strip_html("<ul><li>one</li><li>two</li></ul>"), "one  two");
+    }
+
+    #[test]
+    fn strip_html_removes_self_closing_tags() {
+        assert_eq!(strip_html("line one<br/>line two"), "line one line two");
+        assert_eq!(strip_html("line one<br />line two"), "line one line two");
+        assert_eq!(
+            strip_html("a <img src=\"a.png\" alt=\"x > y\"/>picture"),
+            "a picture"
+        );
+    }
+
+    #[test]
+    fn strip_html_handles_malformed_markup() {
+        assert_eq!(strip_html("a < b and c > d"), "a < b and c > d");
+        assert_eq!(strip_html("x <b>bold"), "x bold");
+        assert_eq!(strip_html("unclosed <p class=\"x"), "unclosed");
+        assert_eq!(strip_html("stray </p> end tag"), "stray   end tag");
+        assert_eq!(strip_html("<<b>>"), "<>");
+    }
+
+    #[test]
+    fn strip_html_removes_comments() {
+        assert_eq!(
+            strip_html("before<!-- <p>hidden</p> -->after"),
+            "beforeafter"
+        );
+        assert_eq!(strip_html("before<!-- never closed <p>text</p>"), "before");
+    }
+
+    #[test]
+    fn strip_html_unescapes_entities() {
+        assert_eq!(
+            strip_html("Fish &amp; chips &lt;3 &#x1F980; &#65; &bogus; &"),
+            "Fish & chips <3 🦀 A &bogus; &"
+        );
+    }
+
+    #[test]
+    fn strip_gemtext_removes_links_and_heading_markers() {
+        assert_eq!(
+            strip_gemtext(
+                "# Title\n=> gemini://localhost/ link\nSome text\n### Sub\n* item\n> quote"
+            ),
+            "Title\nSome text\nSub\n* item\n> quote"
+        );
+    }
+
+    #[test]
+    fn sha256_of_a_string_is_a_subresource_integrity_hash() {
+        let sha256 = |template: &str| {
+            render_with_helper(
+                "sha256",
+                sha256_helper {
+                    server_context: Weak::new(),
+                },
+                template,
+                json!({ "empty": "" }),
+            )
+        };
+
+        // Triple-stashed as HTML escaping would change the = padding
+        assert_eq!(
+            sha256("{{{sha256 \"abc\"}}}"),
+            "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="
+        );
+        assert_eq!(
+            sha256("{{{sha256 empty}}}"),
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
 }
@@ -1,8 +1,14 @@
+use chrono::{DateTime, Utc};
 use rustls::crypto::{aws_lc_rs as provider, CryptoProvider};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::server::WebPkiClientVerifier;
+use rustls::server::{
+    ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni, WebPkiClientVerifier,
+};
+use rustls::sign::CertifiedKey;
 use rustls::RootCertStore;
+use sha2::{Digest, Sha256};
 use std::io::BufReader;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::{fmt, fs, str};
 use tokio::net::TcpStream;
@@ -15,6 +21,11 @@ use crate::config::Config;
 #[derive(Clone)]
 pub struct ClientCertificateDetails {
     common_name: Option<String>,
+    serial: Option<String>,
+    not_after: Option<DateTime<Utc>>,
+    sans: Vec<String>,
+    organization: Option<String>,
+    fingerprint: Option<String>,
 }
 
 impl fmt::Display for ClientCertificateDetails {
@@ -29,7 +40,14 @@ impl fmt::Display for ClientCertificateDetails {
 
 impl ClientCertificateDetails {
     pub fn new_anonymous() -> ClientCertificateDetails {
-        ClientCertificateDetails { common_name: None }
+        ClientCertificateDetails {
+            common_name: None,
+            serial: None,
+            not_after: None,
+            sans: Vec::new(),
+            organization: None,
+            fingerprint: None,
+        }
     }
 
     pub fn is_anonymous(&self) -> bool {
@@ -42,27 +60,97 @@ impl ClientCertificateDetails {
             None => "anonymous".to_string(),
         }
     }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    pub fn not_after(&self) -> Option<&DateTime<Utc>> {
+        self.not_after.as_ref()
+    }
+
+    pub fn sans(&self) -> &[String] {
+        &self.sans
+    }
+
+    pub fn organization(&self) -> Option<&str> {
+        self.organization.as_deref()
+    }
+
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+}
+
+// DNS names, email addresses, URIs and IP addresses (other SAN types are ignored)
+fn subject_alternative_names(cert: &X509Certificate) -> Vec<String> {
+    match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|general_name| match general_name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::RFC822Name(email) => Some(email.to_string()),
+                GeneralName::URI(uri) => Some(uri.to_string()),
+                GeneralName::IPAddress(ip) => match ip.len() {
+                    4 => <[u8; 4]>::try_from(*ip)
+                        .ok()
+                        .map(|octets| IpAddr::from(octets).to_string()),
+                    16 => <[u8; 16]>::try_from(*ip)
+                        .ok()
+                        .map(|octets| IpAddr::from(octets).to_string()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
 }
 
 pub fn extract_client_certificate_details_from_stream(
     stream: &TlsStream<TcpStream>,
 ) -> ClientCertificateDetails {
-    let cert = match stream.get_ref().1.peer_certificates() {
-        Some(der_certs) => match der_certs.iter().next() {
-            Some(first_der_cert) => match parse_x509_certificate(first_der_cert) {
-                Ok((_, cert)) => Some(cert),
-                Err(_) => None,
-            },
-            None => None,
+    let first_der_cert = match stream.get_ref().1.peer_certificates() {
+        Some(der_certs) => der_certs.iter().next(),
+        None => None,
+    };
+
+    let cert = match first_der_cert {
+        Some(first_der_cert) => match parse_x509_certificate(first_der_cert) {
+            Ok((_, cert)) => Some(cert),
+            Err(_) => None,
         },
         None => None,
     };
 
+    // SHA-256 of the DER bytes as lowercase hex, as commonly used to identify Gemini clients
+    let fingerprint = first_der_cert.map(|first_der_cert| {
+        Sha256::digest(first_der_cert)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    });
+
     let details = match cert.clone() {
         Some(cert_data) => match cert_data.subject().iter_common_name().next() {
             Some(cn) => match cn.as_str() {
                 Ok(cn_str) => Some(ClientCertificateDetails {
                     common_name: Some(cn_str.to_string()),
+                    serial: Some(cert_data.raw_serial_as_string()),
+                    not_after: DateTime::from_timestamp(
+                        cert_data.validity().not_after.timestamp(),
+                        0,
+                    ),
+                    sans: subject_alternative_names(&cert_data),
+                    organization: cert_data
+                        .subject()
+                        .iter_organization()
+                        .next()
+                        .and_then(|organization| organization.as_str().ok())
+                        .map(|organization| organization.to_string()),
+                    fingerprint: fingerprint,
                 }),
                 Err(_) => None,
             },
@@ -74,11 +162,38 @@ pub fn extract_client_certificate_details_from_stream(
     details.unwrap_or(ClientCertificateDetails::new_anonymous())
 }
 
+// Picks a certificate by SNI hostname, falling back to the primary certificate
+#[derive(Debug)]
+struct SniCertResolver {
+    sni_resolver: ResolvesServerCertUsingSni,
+    default_certified_key: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.sni_resolver
+            .resolve(client_hello)
+            .or(Some(self.default_certified_key.clone()))
+    }
+}
+
+fn load_certified_key(
+    certificate_pem_filename: &str,
+    private_key_pem_filename: &str,
+) -> CertifiedKey {
+    let certs = load_certs(certificate_pem_filename);
+    let privkey = load_private_key(private_key_pem_filename);
+    let signing_key = provider::sign::any_supported_type(&privkey).expect("bad private key");
+
+    CertifiedKey::new(certs, signing_key)
+}
+
 pub fn make_config(config: &Config) -> Arc<rustls::ServerConfig> {
-    let client_root_certs = load_certs(config.tls_client_ca_certificate_pem_filename());
     let mut client_auth_roots = RootCertStore::empty();
-    for root in client_root_certs {
-        client_auth_roots.add(root).unwrap();
+    for filename in config.tls_client_ca_certificate_pem_filenames() {
+        for root in load_certs(filename) {
+            client_auth_roots.add(root).unwrap();
+        }
     }
     let client_auth = WebPkiClientVerifier::builder(client_auth_roots.into())
         .allow_unauthenticated()
@@ -88,8 +203,23 @@ pub fn make_config(config: &Config) -> Arc<rustls::ServerConfig> {
     let versions = rustls::ALL_VERSIONS.to_vec();
     let suites = provider::ALL_CIPHER_SUITES.to_vec();
 
-    let certs = load_certs(config.tls_server_certificate_pem_filename());
-    let privkey = load_private_key(config.tls_server_private_key_pem_filename());
+    let mut sni_resolver = ResolvesServerCertUsingSni::new();
+    for (hostname, (certificate_pem_filename, private_key_pem_filename)) in config.tls_sni_certs() {
+        sni_resolver
+            .add(
+                hostname,
+                load_certified_key(certificate_pem_filename, private_key_pem_filename),
+            )
+            .expect("bad SNI certificate/private key (does the certificate match the hostname?)");
+    }
+
+    let cert_resolver = SniCertResolver {
+        sni_resolver: sni_resolver,
+        default_certified_key: Arc::new(load_certified_key(
+            config.tls_server_certificate_pem_filename(),
+            config.tls_server_private_key_pem_filename(),
+        )),
+    };
 
     let mut server_config = rustls::ServerConfig::builder_with_provider(
         CryptoProvider {
@@ -101,8 +231,7 @@ pub fn make_config(config: &Config) -> Arc<rustls::ServerConfig> {
     .with_protocol_versions(&versions)
     .expect("inconsistent cipher-suites/versions specified")
     .with_client_cert_verifier(client_auth)
-    .with_single_cert(certs, privkey)
-    .expect("bad certificates/private key");
+    .with_cert_resolver(Arc::new(cert_resolver));
 
     server_config.key_log = Arc::new(rustls::KeyLogFile::new());
 